@@ -13,6 +13,7 @@ in VertexData {
     vec3 pos;
     flat int[3] materials;
     float[3] weights;
+    float ao;
 } VertexIn;
 
 out vec4 FragColor;
@@ -38,5 +39,8 @@ void main() {
 
   float diff = 0.005 + max(0.0, dot(normalize(VertexIn.normal), u_LightDir))*0.995;
 
-  FragColor = vec4(diff * sample, 1.0);
+  // Corners get at most half as much light
+  float occlusion = 0.5 + 0.5*VertexIn.ao;
+
+  FragColor = vec4(diff * occlusion * sample, 1.0);
 }
\ No newline at end of file
@@ -12,6 +12,7 @@ in VertexData {
     vec3 normal;
     vec3 pos;
     int material;
+    float ao;
 } VertexIn[3];
 
 out VertexData {
@@ -19,12 +20,14 @@ out VertexData {
     vec3 pos;
     flat int[3] materials;
     float[3] weights;
+    float ao;
 } VertexOut;
 
 void copyVertex (int i) {
     gl_Position = gl_in[i].gl_Position;
     VertexOut.normal = VertexIn[i].normal;
     VertexOut.pos = VertexIn[i].pos;
+    VertexOut.ao = VertexIn[i].ao;
 }
 
 void setWeight (int i) {
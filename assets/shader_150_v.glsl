@@ -8,11 +8,13 @@ uniform World {
 in vec3 a_Pos;
 in vec3 a_Normal;
 in int a_Material;
+in float a_Ao;
 
 out VertexData {
     vec3 normal;
     vec3 pos;
     int material;
+    float ao;
 } VertexOut;
 
 void main() {
@@ -20,4 +22,5 @@ void main() {
     VertexOut.normal = a_Normal;
     VertexOut.pos = a_Pos;
     VertexOut.material = a_Material;
+    VertexOut.ao = a_Ao;
 }
\ No newline at end of file
@@ -20,6 +20,7 @@ gfx_defines! {
         pos: [f32; 3] = "a_Pos",
         normal: [f32; 3] = "a_Normal",
         material: i32 = "a_Material",
+        ao: f32 = "a_Ao",
     }
 
     constant World {
@@ -1,44 +1,103 @@
 
-use voxel_source::VoxelSource;
+use voxel_source::{VoxelSource, Region};
 use cgmath::Vector3;
 use mesher::Mesher;
 use mesh::{Mesh, Vertex};
 
-pub struct Blocky { pub size: i32 }
+// With greedy, the coplanar faces of the same material are merged into
+// rectangles, as big as they can be
+pub struct Blocky { pub size: i32, pub greedy: bool }
 
-pub struct Builder<'a> {
+// Corners of the faces in each axis
+fn offsets (axis: u8) -> [[i32; 3]; 4] {
+    match axis {
+        0 => [[0, 0, 0],
+              [0, 0, 1],
+              [0, 1, 0],
+              [0, 1, 1]],
+        1 => [[0, 0, 0],
+              [0, 0, 1],
+              [1, 0, 0],
+              [1, 0, 1]],
+        2 => [[0, 0, 0],
+              [1, 0, 0],
+              [0, 1, 0],
+              [1, 1, 0]],
+        _ => unreachable!()
+    }
+}
+
+pub struct Builder {
     size: i32,
-    source: &'a VoxelSource,
+    // Has an extra voxel at each side, to know which faces are exposed
+    region: Region,
     mesh: Mesh,
 }
 
-impl<'a> Builder<'a> {
-    fn new (source: &'a VoxelSource, size: i32) -> Self {
+impl Builder {
+    fn new (source: &dyn VoxelSource, size: i32) -> Self {
+        let mut region = Region::new([-1, -1, -1], [size+2, size+2, size+2], 1);
+        source.fill_region(&mut region);
         Builder {
-            size: size,
-            source: source,
+            size,
+            region,
             mesh: Mesh::new(),
         }
     }
 
-    fn face (&mut self, x: i32, y: i32, z: i32, axis: u8, reverse: bool) {
-        let index = self.mesh.vertices.len() as u16;
-        let offs = match axis {
-            0 => [[0, 0, 0],
-                  [0, 0, 1],
-                  [0, 1, 0],
-                  [0, 1, 1]],
-            1 => [[0, 0, 0],
-                  [0, 0, 1],
-                  [1, 0, 0],
-                  [1, 0, 1]],
-            2 => [[0, 0, 0],
-                  [1, 0, 0],
-                  [0, 1, 0],
-                  [1, 1, 0]],
-            _ => unreachable!()
+    fn is_solid (&self, x: i32, y: i32, z: i32) -> bool {
+        self.region.is_solid(x+1, y+1, z+1)
+    }
+
+    // Classic corner ambient occlusion of the vertex at p, from the three
+    // voxels around it in the empty layer next to the face. dir has 1 or -1
+    // in the other two axes, pointing out of the face from the vertex.
+    fn ao (&self, p: [i32; 3], axis: usize, layer: i32, dir: [i32; 3]) -> u8 {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+        // The voxel in front of the face at that corner
+        let mut front = p;
+        front[axis] = layer;
+        front[u] -= (dir[u] + 1) / 2;
+        front[v] -= (dir[v] + 1) / 2;
+
+        let solid = |du: i32, dv: i32| {
+            let mut q = front;
+            q[u] += du * dir[u];
+            q[v] += dv * dir[v];
+            self.is_solid(q[0], q[1], q[2]) as u8
         };
 
+        let (side1, side2, corner) = (solid(1, 0), solid(0, 1), solid(1, 1));
+        if side1 == 1 && side2 == 1 { 0 } else { 3 - side1 - side2 - corner }
+    }
+
+    // The occlusion of each vertex of a face, in the order of offsets
+    fn face_ao (&self, p: [i32; 3], axis: u8, reverse: bool, size: [i32; 3]) -> [u8; 4] {
+        let axis = axis as usize;
+        // x faces are reversed the other way around
+        let layer = if reverse == (axis == 0) { p[axis] } else { p[axis] - 1 };
+
+        let offs = offsets(axis as u8);
+        let mut ao = [3; 4];
+        for i in 0 .. 4 {
+            let mut vertex = p;
+            let mut dir = [0; 3];
+            for a in 0 .. 3 {
+                vertex[a] += offs[i][a] * size[a];
+                dir[a] = offs[i][a]*2 - 1;
+            }
+            ao[i] = self.ao(vertex, axis, layer, dir);
+        }
+        ao
+    }
+
+    // The face is scaled by size in each axis, for the merged faces
+    fn face (&mut self, p: [i32; 3], axis: u8, reverse: bool, material: u8, size: [i32; 3]) {
+        let index = self.mesh.vertices.len() as u32;
+        let offs = offsets(axis);
+        let ao = self.face_ao(p, axis, reverse, size);
+
         let axoff = match (axis, reverse) {
             (0, false) => [1, 0, 0],
             (0, true) => [-1, 0, 0],
@@ -53,34 +112,136 @@ impl<'a> Builder<'a> {
             let off = offs[i];
             self.mesh.vertices.push(Vertex{
                 pos: Vector3::new(
-                    (x + off[0]) as f32,
-                    (y + off[1]) as f32,
-                    (z + off[2]) as f32
+                    (p[0] + off[0]*size[0]) as f32,
+                    (p[1] + off[1]*size[1]) as f32,
+                    (p[2] + off[2]*size[2]) as f32
                 ),
                 normal: Vector3::new(
                     axoff[0] as f32,
                     axoff[1] as f32,
                     axoff[2] as f32
-                )
+                ),
+                material,
+                ao: ao[i],
             });
         }
 
-        let order = if reverse {[2,1,0, 3,1,2]} else {[0,1,2, 2,1,3]};
+        // Split the quad along the diagonal that doesn't touch the darkest
+        // corner, otherwise the occlusion is interpolated unevenly
+        let flip = ao[0] + ao[3] > ao[1] + ao[2];
+        let order = match (reverse, flip) {
+            (false, false) => [0,1,2, 2,1,3],
+            (false, true) => [0,1,3, 0,3,2],
+            (true, false) => [2,1,0, 3,1,2],
+            (true, true) => [3,1,0, 2,3,0],
+        };
         for i in 0..6 { self.mesh.indices.push(index + order[i]); }
     }
 
     fn cube (&mut self, x: i32, y: i32, z: i32) {
         // Cancel empty voxels
-        if !self.source.get(x, y, z) { return; }
+        if !self.is_solid(x, y, z) { return; }
+
+        // All the faces of a cube share it's material
+        let m = self.region.material(x+1, y+1, z+1);
+
+        let one = [1, 1, 1];
+
+        if !self.is_solid(x+1, y, z) { self.face([x+1, y, z], 0, true, m, one); }
+        if !self.is_solid(x-1, y, z) { self.face([x  , y, z], 0, false, m, one); }
+
+        if !self.is_solid(x, y+1, z) { self.face([x, y+1, z], 1, false, m, one); }
+        if !self.is_solid(x, y-1, z) { self.face([x, y  , z], 1, true, m, one); }
 
-        if !self.source.get(x+1, y, z) { self.face(x+1, y, z, 0, true); }
-        if !self.source.get(x-1, y, z) { self.face(x  , y, z, 0, false); }
+        if !self.is_solid(x, y, z+1) { self.face([x, y, z+1], 2, false, m, one); }
+        if !self.is_solid(x, y, z-1) { self.face([x, y, z  ], 2, true, m, one); }
+    }
+
+    // The face between the voxel at d-1 and the one at d in the axis, with
+    // the other two axes at i and j. The same face cube would make, if any,
+    // with it's reverse, material and occlusion.
+    fn slice_face (&self, axis: usize, d: i32, i: i32, j: i32) -> Option<(bool, u8, [u8; 4])> {
+        let mut a = [0; 3];
+        a[axis] = d - 1;
+        a[(axis + 1) % 3] = i;
+        a[(axis + 2) % 3] = j;
+        let mut b = a;
+        b[axis] = d;
+
+        let solid_a = self.is_solid(a[0], a[1], a[2]);
+        let solid_b = self.is_solid(b[0], b[1], b[2]);
+
+        // Only the faces of voxels inside of the chunk. x faces are reversed
+        // the other way around, because of their vertex order.
+        let (reverse, material) = if solid_a && !solid_b && d > 0 {
+            (axis == 0, self.region.material(a[0]+1, a[1]+1, a[2]+1))
+        } else if solid_b && !solid_a && d < self.size {
+            (axis != 0, self.region.material(b[0]+1, b[1]+1, b[2]+1))
+        } else {
+            return None;
+        };
+
+        let ao = self.face_ao(b, axis as u8, reverse, [1, 1, 1]);
+        Some((reverse, material, ao))
+    }
+
+    fn build_greedy (&mut self) {
+        let size = self.size;
+        let mut mask = vec![None; (size*size) as usize];
+
+        for axis in 0 .. 3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+            for d in 0 .. size+1 {
+                for j in 0 .. size {
+                    for i in 0 .. size {
+                        mask[(i + j*size) as usize] = self.slice_face(axis, d, i, j);
+                    }
+                }
 
-        if !self.source.get(x, y+1, z) { self.face(x, y+1, z, 1, false); }
-        if !self.source.get(x, y-1, z) { self.face(x, y  , z, 1, true); }
+                for j in 0 .. size {
+                    let mut i = 0;
+                    while i < size {
+                        let face = mask[(i + j*size) as usize];
+                        let (reverse, material, ao) = match face {
+                            Some(face) => face,
+                            None => { i += 1; continue; }
+                        };
 
-        if !self.source.get(x, y, z+1) { self.face(x, y, z+1, 2, false); }
-        if !self.source.get(x, y, z-1) { self.face(x, y, z  , 2, true); }
+                        // Only faces with the same occlusion at every corner
+                        // look the same when merged
+                        let merge = ao.iter().all(|&a| a == ao[0]);
+
+                        // As wide as possible, then as tall as every row allows
+                        let mut w = 1;
+                        while merge && i+w < size && mask[(i+w + j*size) as usize] == face { w += 1; }
+
+                        let mut h = 1;
+                        while merge && j+h < size &&
+                            (0 .. w).all(|k| mask[(i+k + (j+h)*size) as usize] == face) {
+                            h += 1;
+                        }
+
+                        for l in 0 .. h {
+                            for k in 0 .. w {
+                                mask[(i+k + (j+l)*size) as usize] = None;
+                            }
+                        }
+
+                        let mut p = [0; 3];
+                        p[axis] = d;
+                        p[u] = i;
+                        p[v] = j;
+                        let mut extent = [1; 3];
+                        extent[u] = w;
+                        extent[v] = h;
+                        self.face(p, axis as u8, reverse, material, extent);
+
+                        i += w;
+                    }
+                }
+            }
+        }
     }
 
     fn build (&mut self) {
@@ -101,7 +262,11 @@ impl Mesher for Blocky {
         let now = ::std::time::Instant::now();
 
         let mut builder = Builder::new(source, self.size);
-        builder.build();
+        if self.greedy {
+            builder.build_greedy();
+        } else {
+            builder.build();
+        }
 
         let tm = now.elapsed();
         println!("The craft was mined in {} ms, with {} vertices",
@@ -111,5 +276,6 @@ impl Mesher for Blocky {
 
         builder.mesh
     }
-}
 
+    fn size (&self) -> i32 { self.size }
+}
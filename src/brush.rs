@@ -0,0 +1,149 @@
+
+use voxel_source::{VoxelSource, EditSource, Voxel, Region, Bounds};
+
+#[derive(Clone, Copy)]
+pub enum Shape { Sphere, Cube }
+
+// Sculpting tools. All of them take a center, a radius and a strength in
+// [0, 1]. The center and radius are in world voxels.
+#[derive(Clone, Copy)]
+pub enum Brush {
+    // Makes the shape solid, with the given material
+    Add(Shape, u8),
+    // Digs the shape out
+    Remove(Shape),
+    // Averages each voxel with its neighbours
+    Smooth,
+    // Moves the terrain towards the plane through the center with this normal
+    Flatten([f32; 3]),
+    // Changes the material of the solid voxels, stronger paints wider
+    Paint(u8),
+}
+
+impl Shape {
+    // Like a density, positive inside
+    fn density (&self, d: [f32; 3], radius: f32) -> f32 {
+        match *self {
+            Shape::Sphere => radius - (d[0]*d[0] + d[1]*d[1] + d[2]*d[2]).sqrt(),
+            Shape::Cube => radius - d[0].abs().max(d[1].abs()).max(d[2].abs()),
+        }
+    }
+}
+
+fn lerp (a: f32, b: f32, t: f32) -> f32 { a + (b - a)*t }
+
+impl Brush {
+    fn shape (&self) -> Shape {
+        match *self {
+            Brush::Add(shape, _) | Brush::Remove(shape) => shape,
+            _ => Shape::Sphere,
+        }
+    }
+
+    // Returns the voxels that changed, if any did
+    pub fn apply<S: VoxelSource> (
+            &self,
+            world: &mut EditSource<S>,
+            center: [f32; 3],
+            radius: f32,
+            strength: f32
+        ) -> Option<Bounds> {
+
+        let strength = strength.clamp(0.0, 1.0);
+        let bounds = Bounds::around(center, radius);
+
+        // Current state of the area, with an extra voxel for smoothing
+        let min = [bounds.min[0]-1, bounds.min[1]-1, bounds.min[2]-1];
+        let mut size = [0; 3];
+        for i in 0 .. 3 { size[i] = bounds.max[i] - min[i] + 2; }
+        let mut region = Region::new(min, size, 1);
+        world.fill_region(&mut region);
+
+        let mut changed: Option<Bounds> = None;
+
+        for z in bounds.min[2] .. bounds.max[2]+1 {
+            for y in bounds.min[1] .. bounds.max[1]+1 {
+                for x in bounds.min[0] .. bounds.max[0]+1 {
+                    let d = [
+                        x as f32 - center[0],
+                        y as f32 - center[1],
+                        z as f32 - center[2],
+                    ];
+                    if self.shape().density(d, radius) < 0.0 { continue; }
+
+                    let (i, j, k) = (x - min[0], y - min[1], z - min[2]);
+                    let old = region.get(i, j, k);
+                    let new = self.voxel(&region, [i, j, k], d, radius, strength);
+
+                    if new != old {
+                        world.set(x, y, z, new);
+                        let point = Bounds::point(x, y, z);
+                        changed = Some(match changed {
+                            Some(b) => b.union(&point),
+                            None => point,
+                        });
+                    }
+                }
+            }
+        }
+
+        changed
+    }
+
+    // The new value of the voxel at p in the region, at d from the center
+    fn voxel (
+            &self,
+            region: &Region,
+            p: [i32; 3],
+            d: [f32; 3],
+            radius: f32,
+            strength: f32
+        ) -> Voxel {
+
+        let shape = self.shape().density(d, radius);
+        // 1 at the center, 0 at the border
+        let falloff = shape / radius;
+
+        let old = region.get(p[0], p[1], p[2]);
+        let mut new = old;
+        match *self {
+            Brush::Add(_, material) => {
+                // Only where the shape is more solid than the terrain
+                if shape > old.density {
+                    new.density = lerp(old.density, shape, strength);
+                    new.material = material;
+                }
+            },
+            Brush::Remove(_) => {
+                if -shape < old.density {
+                    new.density = lerp(old.density, -shape, strength);
+                }
+            },
+            Brush::Smooth => {
+                let mut sum = 0.0;
+                for k in -1 .. 2 {
+                    for j in -1 .. 2 {
+                        for i in -1 .. 2 {
+                            sum += region.density(p[0]+i, p[1]+j, p[2]+k);
+                        }
+                    }
+                }
+                new.density = lerp(old.density, sum / 27.0, strength * falloff);
+            },
+            Brush::Flatten(normal) => {
+                let len = (normal[0]*normal[0] + normal[1]*normal[1] + normal[2]*normal[2]).sqrt();
+                if len > 0.0 {
+                    // Solid under the plane
+                    let plane = -(d[0]*normal[0] + d[1]*normal[1] + d[2]*normal[2]) / len;
+                    new.density = lerp(old.density, plane, strength * falloff);
+                }
+            },
+            Brush::Paint(material) => {
+                if old.is_solid() && falloff >= 1.0 - strength {
+                    new.material = material;
+                }
+            },
+        }
+        new
+    }
+}
@@ -98,6 +98,12 @@ impl Camera {
         * Matrix4::from_translation(-self.pos)
     }
 
+    // Direction the camera looks at
+    pub fn forward (&self) -> Vector3<f32> {
+        let rotation = Matrix4::from_angle_y(-self.yaw) * Matrix4::from_angle_x(-self.pitch);
+        rotation.transform_vector(Vector3::new(0.0, 0.0, -1.0))
+    }
+
     pub fn update (&mut self) {
         let mut mov = Vector3::new(0.0, 0.0, 0.0);
         if self.up    { mov.y += 1.0; }
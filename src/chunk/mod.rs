@@ -1,12 +1,22 @@
 
-use voxel_source::VoxelSource;
-use mesher::Mesher;
+
+use std::io;
+use std::path::Path;
+use std::collections::HashMap;
+
+use voxel_source::{VoxelSource, Region, Voxel, EditSource, Bounds};
+use mesher::{Mesher, Transition};
+use mesh::{Mesh, Simplify, CacheReport, Report, export};
+use brush::Brush;
+use history::History;
 use base;
 use base::{FactoryExt, Base, Texture};
 
 struct Data {
     vbuf: base::VertexBuffer,
     slice: base::Slice,
+    // How much the optimization helped the vertex cache
+    cache: CacheReport,
 }
 
 pub struct Chunk {
@@ -17,21 +27,229 @@ pub struct Chunk {
   data: Option<Data>
 }
 
+impl Chunk {
+    // The world voxels the chunk's mesh depends on
+    fn bounds (&self, size: i32, padding: i32) -> Bounds {
+        let lo = -padding * self.r;
+        let hi = (size + padding) * self.r;
+        Bounds {
+            min: [self.x*2 + lo, self.y*2 + lo, self.z*2 + lo],
+            max: [self.x*2 + hi, self.y*2 + hi, self.z*2 + hi],
+        }
+    }
+
+    // Whether the world voxel is inside of the chunk
+    fn contains (&self, p: [i32; 3], size: i32) -> bool {
+        let pos = [self.x*2, self.y*2, self.z*2];
+        (0 .. 3).all(|i| pos[i] <= p[i] && p[i] < pos[i] + size*self.r)
+    }
+}
+
+// The chunks by resolution and place in the grid of the chunks of that
+// resolution, to find the chunk of a voxel without looking at all of them.
+// Chunks are placed on the grid of their own size.
+struct ChunkIndex<'a> {
+    chunks: &'a [Chunk],
+    size: i32,
+    resolutions: Vec<i32>,
+    cells: HashMap<(i32, [i32; 3]), usize>,
+}
+
+impl<'a> ChunkIndex<'a> {
+    fn new (chunks: &'a [Chunk], size: i32) -> Self {
+        let mut index = ChunkIndex { chunks, size, resolutions: vec![], cells: HashMap::new() };
+        for (i, chunk) in chunks.iter().enumerate() {
+            if !index.resolutions.contains(&chunk.r) { index.resolutions.push(chunk.r); }
+            index.cells.insert(index.cell([chunk.x*2, chunk.y*2, chunk.z*2], chunk.r), i);
+        }
+        index
+    }
+
+    fn cell (&self, p: [i32; 3], r: i32) -> (i32, [i32; 3]) {
+        let extent = self.size * r;
+        (r, [p[0].div_euclid(extent), p[1].div_euclid(extent), p[2].div_euclid(extent)])
+    }
+
+    // The chunk that has the world voxel
+    fn find (&self, p: [i32; 3]) -> Option<&'a Chunk> {
+        self.resolutions.iter()
+            .filter_map(|&r| self.cells.get(&self.cell(p, r)))
+            .map(|&i| &self.chunks[i])
+            .find(|chunk| chunk.contains(p, self.size))
+    }
+}
+
+// The faces of the chunk that are next to chunks with more detail. A face
+// next to several has one for each different detail.
+fn transitions (chunk: &Chunk, index: &ChunkIndex) -> Vec<Transition> {
+    let size = index.size;
+    let mut faces = vec![];
+
+    // Nothing can have more detail
+    if chunk.r == 1 { return faces; }
+
+    let pos = [chunk.x*2, chunk.y*2, chunk.z*2];
+    for axis in 0 .. 3 {
+        for &positive in [false, true].iter() {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let start = faces.len();
+
+            // The voxel just outside of the face, in the middle of each square
+            let mut p = [0; 3];
+            p[axis] = if positive { pos[axis] + size*chunk.r } else { pos[axis] - 1 };
+
+            for j in 0 .. size {
+                for i in 0 .. size {
+                    p[u] = pos[u] + i*chunk.r + chunk.r/2;
+                    p[v] = pos[v] + j*chunk.r + chunk.r/2;
+
+                    let other = match index.find(p) {
+                        Some(other) => other,
+                        None => continue,
+                    };
+                    if other.r >= chunk.r { continue; }
+                    let detail = chunk.r / other.r;
+
+                    let index = match faces[start..].iter().position(|f: &Transition| f.detail == detail) {
+                        Some(index) => start + index,
+                        None => {
+                            faces.push(Transition {
+                                axis,
+                                positive,
+                                detail,
+                                cells: vec![false; (size*size) as usize],
+                            });
+                            faces.len() - 1
+                        }
+                    };
+                    faces[index].cells[(i + j*size) as usize] = true;
+                }
+            }
+        }
+    }
+
+    faces
+}
+
+// Discards the meshes of the chunks that read any of the voxels, and
+// returns their positions
+fn mark_dirty (chunks: &mut [Chunk], bounds: &Bounds, size: i32, padding: i32) -> Vec<(i32, i32, i32)> {
+    let mut dirty = vec![];
+    for chunk in chunks.iter_mut() {
+        if chunk.bounds(size, padding).intersects(bounds) {
+            chunk.data = None;
+            dirty.push((chunk.x, chunk.y, chunk.z));
+        }
+    }
+    dirty
+}
+
 pub struct ChunkSource<'a> {
   orig: &'a VoxelSource,
   chunk: &'a Chunk,
 }
 
 impl<'a> VoxelSource for ChunkSource<'a> {
-  fn get(&self, x: i32, y: i32, z: i32) -> bool {
+  fn density(&self, x: i32, y: i32, z: i32) -> f32 {
     let c = &self.chunk;
     // Voxels are half a meter big
-    self.orig.get(
+    self.orig.density(
         x * c.r + c.x*2,
         y * c.r + c.y*2,
         z * c.r + c.z*2
     )
   }
+
+  fn material(&self, x: i32, y: i32, z: i32) -> u8 {
+    let c = &self.chunk;
+    self.orig.material(
+        x * c.r + c.x*2,
+        y * c.r + c.y*2,
+        z * c.r + c.z*2
+    )
+  }
+
+  // Scaling doesn't change the direction
+  fn normal(&self, p: [f32; 3]) -> Option<[f32; 3]> {
+    let c = &self.chunk;
+    let r = c.r as f32;
+    self.orig.normal([
+        p[0] * r + (c.x*2) as f32,
+        p[1] * r + (c.y*2) as f32,
+        p[2] * r + (c.z*2) as f32,
+    ])
+  }
+
+  fn fill_region(&self, region: &mut Region) {
+    let c = &self.chunk;
+    let (origin, step) = (region.origin, region.step);
+    region.origin = [
+        origin[0] * c.r + c.x*2,
+        origin[1] * c.r + c.y*2,
+        origin[2] * c.r + c.z*2,
+    ];
+    region.step = step * c.r;
+    self.orig.fill_region(region);
+    region.origin = origin;
+    region.step = step;
+  }
+}
+
+// Vertices closer than this, in voxels of the chunk, are joined
+const WELD_TOLERANCE: f32 = 1e-4;
+
+// The mesh of the chunk, in meters, with the transitions to the faces, and
+// the cache report of it's optimization
+fn build (
+        source: &dyn VoxelSource,
+        mesher: &mut dyn Mesher,
+        simplify: &[(i32, Simplify)],
+        chunk: &Chunk,
+        faces: &[Transition]
+    ) -> (Mesh, CacheReport) {
+    let size = mesher.size();
+    let mut mesh = mesher.mesh(&ChunkSource {
+        orig: source, chunk
+    });
+
+    // The vertices on the faces of the chunk stay, the chunks next to it
+    // and the transitions need them there. Blocky repeats the vertices of
+    // each face, they're joined first so the edges between the faces are
+    // not taken for borders.
+    if let Some((_, params)) = simplify.iter().find(|&&(r, _)| r == chunk.r) {
+        mesh.weld(WELD_TOLERANCE);
+        let max = size as f32;
+        mesh.simplify(params, &|vertex| {
+            let p = vertex.pos;
+            [p.x, p.y, p.z].iter().any(|&c| c <= 0.0 || c >= max)
+        });
+    }
+
+    // Close the cracks with the chunks with more detail. The source for
+    // those is this chunk with the most detail of them.
+    if let Some(detail) = faces.iter().map(|face| face.detail).max() {
+        let fine = Chunk {
+            x: chunk.x, y: chunk.y, z: chunk.z,
+            r: chunk.r / detail,
+            data: None,
+        };
+        mesher.transition(&mut mesh, &ChunkSource { orig: source, chunk: &fine }, faces);
+    }
+
+    // Blocky repeats the vertices of each face, and the meshers make the
+    // triangles in the order of the cells, not the best for the GPU
+    let report = mesh.optimize(WELD_TOLERANCE);
+
+    // Voxels are half a meter big
+    mesh.scale(chunk.r as f32 * 0.5);
+
+    mesh.translate(::mesh::Vector3::new(
+        chunk.x as f32,
+        chunk.y as f32,
+        chunk.z as f32
+    ));
+
+    (mesh, report)
 }
 
 // In the future, use this for infinite voxels
@@ -39,8 +257,11 @@ impl<'a> VoxelSource for ChunkSource<'a> {
 
 pub struct ChunkManager {
   chunks: Vec<Chunk>,
-  source: Box<VoxelSource>,
+  source: EditSource<Box<dyn VoxelSource>>,
+  history: History,
   mesher: Box<Mesher>,
+  // For the chunks of each resolution that are simplified
+  simplify: Vec<(i32, Simplify)>,
   modified: bool,
   grass_texture: Texture,
   soilsand_texture: Texture,
@@ -58,8 +279,10 @@ impl ChunkManager {
         );
         ChunkManager{
             chunks: vec![],
-            source: Box::new(s),
+            source: EditSource::new(Box::new(s)),
+            history: History::new(),
             mesher: Box::new(m),
+            simplify: vec![],
             modified: false,
             grass_texture: base.load_texture("assets/grass.jpg"),
             soilsand_texture: base.load_texture("assets/soilsand.jpg"),
@@ -71,7 +294,20 @@ impl ChunkManager {
         for chunk in self.chunks.iter() {
             if chunk.x == x && chunk.y == y && chunk.z == z { return; }
         }
-        self.chunks.push(Chunk{ x: x, y: y, z: z, r: r, data: None });
+        let chunk = Chunk{ x, y, z, r, data: None };
+        let extent = self.mesher.size() * r;
+        debug_assert!([x*2, y*2, z*2].iter().all(|v| v % extent == 0), "chunks go on the grid of their size");
+
+        // The neighbors may need transitions to it now
+        let size = self.mesher.size();
+        let bounds = chunk.bounds(size, 0);
+        for other in self.chunks.iter_mut() {
+            if other.bounds(size, 1).intersects(&bounds) {
+                other.data = None;
+            }
+        }
+
+        self.chunks.push(chunk);
         self.modified = true;
     }
 
@@ -83,50 +319,181 @@ impl ChunkManager {
         self.modified = true;
     }
 
-    pub fn update (&mut self, base: &mut Base) {
-        if !self.modified { return; }
+    // Meshes another world. The edits and their history are of the old one,
+    // so they are dropped.
+    pub fn set_source <S: VoxelSource + 'static> (&mut self, s: S) {
+        self.source = EditSource::new(Box::new(s));
+        self.history = History::new();
+        for chunk in self.chunks.iter_mut() {
+            chunk.data = None;
+        }
+        self.modified = true;
+    }
+
+    // Simplifies the meshes of the chunks with that resolution, or stops
+    // doing it with None
+    pub fn set_simplify (&mut self, r: i32, simplify: Option<Simplify>) {
+        self.simplify.retain(|&(level, _)| level != r);
+        if let Some(simplify) = simplify {
+            self.simplify.push((r, simplify));
+        }
+
+        for chunk in self.chunks.iter_mut().filter(|chunk| chunk.r == r) {
+            chunk.data = None;
+        }
+        self.modified = true;
+    }
+
+    // Changes a voxel, in world voxel coordinates. Returns the position of
+    // the chunks that have to be meshed again.
+    pub fn set (&mut self, x: i32, y: i32, z: i32, voxel: Voxel) -> Vec<(i32, i32, i32)> {
+        let bounds = self.history.record(&mut self.source, Bounds::point(x, y, z), |source| {
+            Some(source.set(x, y, z, voxel))
+        });
+        self.mark_dirty_some(bounds)
+    }
+
+    // Returns the position of the chunks that have to be meshed again
+    pub fn apply_brush (
+            &mut self,
+            brush: &Brush,
+            center: [f32; 3],
+            radius: f32,
+            strength: f32
+        ) -> Vec<(i32, i32, i32)> {
+        let area = Bounds::around(center, radius);
+        let bounds = self.history.record(&mut self.source, area, |source| {
+            brush.apply(source, center, radius, strength)
+        });
+        self.mark_dirty_some(bounds)
+    }
+
+    // Edits made between these two are undone and redone together
+    pub fn begin_transaction (&mut self) {
+        self.history.begin();
+    }
+
+    pub fn commit_transaction (&mut self) {
+        self.history.commit();
+    }
+
+    pub fn undo (&mut self) -> Vec<(i32, i32, i32)> {
+        let bounds = self.history.undo(&mut self.source);
+        self.mark_dirty_some(bounds)
+    }
+
+    pub fn redo (&mut self) -> Vec<(i32, i32, i32)> {
+        let bounds = self.history.redo(&mut self.source);
+        self.mark_dirty_some(bounds)
+    }
+
+    fn mark_dirty_some (&mut self, bounds: Option<Bounds>) -> Vec<(i32, i32, i32)> {
+        match bounds {
+            Some(bounds) => self.mark_dirty(&bounds),
+            None => vec![],
+        }
+    }
+
+    // Discards the meshes of all chunks that read any of the voxels
+    pub fn mark_dirty (&mut self, bounds: &Bounds) -> Vec<(i32, i32, i32)> {
+        let dirty = mark_dirty(&mut self.chunks, bounds, self.mesher.size(), self.mesher.padding());
+        if !dirty.is_empty() { self.modified = true; }
+        dirty
+    }
+
+    // Writes all the chunks to one file, each as an object. The format
+    // comes from the extension, see mesh::export.
+    pub fn export (&mut self, path: &Path) -> io::Result<()> {
+        let size = self.mesher.size();
+
+        let index = ChunkIndex::new(&self.chunks, size);
+        let mut meshes = vec![];
+        for chunk in self.chunks.iter() {
+            let faces = transitions(chunk, &index);
+            let (mesh, _) = build(&self.source, &mut *self.mesher, &self.simplify, chunk, &faces);
+            meshes.push((format!("chunk_{}_{}_{}", chunk.x, chunk.y, chunk.z), mesh));
+        }
+
+        let objects: Vec<(&str, &Mesh)> = meshes.iter().map(|(name, mesh)| (&name[..], mesh)).collect();
+        export::save(path, &objects)
+    }
+
+    // Meshes all the chunks again and checks them, see mesh::validate.
+    // Returns the reports of the chunks with something wrong.
+    pub fn validate (&mut self) -> Vec<((i32, i32, i32), Report)> {
+        let size = self.mesher.size();
+
+        let index = ChunkIndex::new(&self.chunks, size);
+        let mut reports = vec![];
+        for chunk in self.chunks.iter() {
+            let faces = transitions(chunk, &index);
+            let (mesh, _) = build(&self.source, &mut *self.mesher, &self.simplify, chunk, &faces);
+            let report = mesh.validate();
+            if !report.is_valid() {
+                reports.push(((chunk.x, chunk.y, chunk.z), report));
+            }
+        }
+        reports
+    }
+
+    // The average cache miss ratio of the meshed chunks, before and after
+    // optimizing them
+    pub fn cache_report (&self) -> Option<CacheReport> {
+        let reports: Vec<&CacheReport> = self.chunks.iter().filter_map(|chunk| {
+            chunk.data.as_ref().map(|data| &data.cache)
+        }).collect();
+        if reports.is_empty() { return None; }
+
+        let n = reports.len() as f32;
+        Some(CacheReport {
+            before: reports.iter().map(|r| r.before).sum::<f32>() / n,
+            after: reports.iter().map(|r| r.after).sum::<f32>() / n,
+        })
+    }
+
+    // Meshes the chunks that changed. Returns whether there were any.
+    pub fn update (&mut self, base: &mut Base) -> bool {
+        if !self.modified { return false; }
 
         match self { &mut ChunkManager {
-            ref mut source, ref mut mesher, ref mut chunks, ..
+            ref source, ref mut mesher, ref mut chunks, ref simplify, ..
         } => {
-            for chunk in chunks.iter_mut() {
-                if chunk.data.is_some() { continue; }
+            let size = mesher.size();
+            let index = ChunkIndex::new(chunks, size);
+            let faces: Vec<Vec<Transition>> = chunks.iter().map(|chunk| {
+                if chunk.data.is_some() { vec![] } else { transitions(chunk, &index) }
+            }).collect();
 
-                let mut mesh = mesher.mesh(&ChunkSource {
-                    orig: source.as_ref(), chunk: chunk
-                });
-
-                // Voxels are half a meter big
-                mesh.scale(chunk.r as f32 * 0.5);
+            for (chunk, faces) in chunks.iter_mut().zip(faces) {
+                if chunk.data.is_some() { continue; }
 
-                mesh.translate(::mesh::Vector3::new(
-                    chunk.x as f32,
-                    chunk.y as f32,
-                    chunk.z as f32
-                ));
+                let (mesh, cache) = build(source, &mut **mesher, simplify, chunk, &faces);
 
                 let vertices: Vec<base::Vertex> = mesh.vertices.iter().map( |vertex| {
-                    use cgmath::{Vector2, InnerSpace};
-                    let v2 = Vector2::new(vertex.pos.x, vertex.pos.z);
-                    let i = v2.magnitude() as i32 / 4;
-                    let mat = i%2;
                     base::Vertex {
                         pos: *vertex.pos.as_ref(),
                         normal: *vertex.normal.as_ref(),
-                        material: mat,
+                        material: vertex.material as i32,
+                        ao: vertex.ao as f32 / 3.0,
                     }
                 }).collect();
-                let indices: &[u16] = mesh.indices.as_ref();
+                // 16 bit indices take half the memory, but only reach so
+                // many vertices
+                let (vbuf, slice) = match mesh.short_indices() {
+                    Some(indices) => base.factory.create_vertex_buffer_with_slice(
+                        &vertices, &indices[..]
+                    ),
+                    None => base.factory.create_vertex_buffer_with_slice(
+                        &vertices, &mesh.indices[..]
+                    ),
+                };
 
-                let (vbuf, slice) = base.factory.create_vertex_buffer_with_slice(
-                    &vertices, indices
-                );
-
-                chunk.data = Some(Data{vbuf: vbuf, slice: slice});
+                chunk.data = Some(Data{vbuf, slice, cache});
             }
         } }
 
         self.modified = false;
+        true
     }
 
     pub fn render (&self, base: &mut Base) {
@@ -136,7 +503,7 @@ impl ChunkManager {
 
         for chunk in self.chunks.iter() {
             match chunk.data {
-                Some(Data{ref vbuf, ref slice}) => {
+                Some(Data{ref vbuf, ref slice, ..}) => {
                     let &mut Base {
                         ref mut encoder, ref mut terrain_pso, ..
                     } = base;
@@ -159,3 +526,137 @@ impl ChunkManager {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use marching_cubes::{MarchingCubes, Smoothing};
+    use blocky::Blocky;
+    use voxel_source::SineSource;
+    use voxel_source::sdf::{Sphere, Vector};
+    use brush::Shape;
+    use mesh::Vector3;
+
+    fn chunk (x: i32, y: i32, z: i32, r: i32) -> Chunk {
+        // In voxels of the world, the chunks are in meters
+        Chunk { x: x / 2, y: y / 2, z: z / 2, r, data: None }
+    }
+
+    // The chunks around a sphere with the transitions between them have to
+    // close it, with the vertices of the faces in the same places
+    fn check_closed (chunks: &[Chunk], center: [f32; 3]) {
+        let sphere = Sphere {
+            center: Vector::new(center[0], center[1], center[2]),
+            radius: 6.3,
+            material: 0,
+        };
+        for &manifold in [false, true].iter() {
+            let mut mesher = MarchingCubes { size: 8, smoothing: Smoothing::None, manifold };
+            let mut world = Mesh::new();
+            let index = ChunkIndex::new(chunks, 8);
+            for chunk in chunks.iter() {
+                let faces = transitions(chunk, &index);
+                let (mesh, _) = build(&sphere, &mut mesher, &[], chunk, &faces);
+                let report = mesh.validate();
+                assert!(report.is_valid(), "chunk of r {}: {}", chunk.r, report);
+                let offset = world.vertices.len() as u32;
+                world.indices.extend(mesh.indices.iter().map(|i| i + offset));
+                world.vertices.extend(mesh.vertices);
+            }
+
+            // The chunks with less detail get the normals from fewer voxels
+            for vertex in world.vertices.iter_mut() {
+                vertex.normal = Vector3::new(0.0, 0.0, 0.0);
+            }
+            world.weld(1e-3);
+            let report = world.validate();
+            assert!(report.is_valid() && report.boundary_loops.is_empty(), "manifold {}: {}", manifold, report);
+        }
+    }
+
+    #[test]
+    fn transitions_close_a_face () {
+        let mut chunks = vec![chunk(0, 0, 0, 2)];
+        for &y in [0, 8].iter() { for &z in [0, 8].iter() {
+            chunks.push(chunk(16, y, z, 1));
+        } }
+        check_closed(&chunks, [16.2, 7.7, 8.4]);
+    }
+
+    #[test]
+    fn transitions_close_four_times_the_detail () {
+        let mut chunks = vec![chunk(0, 0, 0, 4)];
+        for y in 0 .. 4 { for z in 0 .. 4 {
+            chunks.push(chunk(32, y*8, z*8, 1));
+        } }
+        check_closed(&chunks, [31.6, 16.3, 15.8]);
+    }
+
+    // The cells of two faces meet at the edge between them
+    #[test]
+    fn transitions_close_an_edge () {
+        let mut chunks = vec![chunk(0, 0, 0, 2)];
+        for &z in [0, 8].iter() {
+            for &y in [0, 8, 16].iter() { chunks.push(chunk(16, y, z, 1)); }
+            for &x in [0, 8].iter() { chunks.push(chunk(x, 16, z, 1)); }
+        }
+        check_closed(&chunks, [16.3, 15.6, 8.2]);
+    }
+
+    // Part of the face is next to chunks with twice the detail, and part to
+    // chunks with four times, that are next to each other too
+    #[test]
+    fn transitions_close_mixed_details () {
+        let mut chunks = vec![chunk(0, 0, 0, 4), chunk(32, 0, 0, 2)];
+        for &y in [16, 24].iter() { for &z in [0, 8].iter() {
+            chunks.push(chunk(32, y, z, 1));
+        } }
+        check_closed(&chunks, [31.7, 16.4, 8.3]);
+    }
+
+    // The faces of blocky chunks are welded before simplifying, else every
+    // vertex is on a border and nothing collapses
+    #[test]
+    fn simplifies_blocky_chunks () {
+        let sphere = Sphere { center: Vector::new(8.3, 7.6, 8.2), radius: 6.4, material: 0 };
+        let mut mesher = Blocky { size: 16, greedy: false };
+        let chunk = chunk(0, 0, 0, 1);
+        let simplify = [(1, Simplify { triangles: 0, error: 1e-4 })];
+
+        let (full, _) = build(&sphere, &mut mesher, &[], &chunk, &[]);
+        let (simple, _) = build(&sphere, &mut mesher, &simplify, &chunk, &[]);
+        assert!(simple.indices.len() < full.indices.len(), "{} of {}", simple.indices.len(), full.indices.len());
+        let report = simple.validate();
+        assert!(report.is_valid(), "{}", report);
+    }
+
+    // A brush at the border of two chunks remeshes both of them, and so do
+    // undoing and redoing it
+    #[test]
+    fn edits_dirty_the_chunks_around () {
+        let (size, padding) = (8, 1);
+        let mut chunks = vec![chunk(0, 0, 0, 1), chunk(8, 0, 0, 1), chunk(16, 0, 0, 1), chunk(0, 0, 8, 2)];
+        let mut world = EditSource::new(SineSource { amplitude: 0.0, magnitude: 0.0, bias: 4.0 });
+        let mut history = History::new();
+
+        let (center, radius) = ([7.6, 4.2, 3.1], 1.5);
+        let brush = Brush::Remove(Shape::Sphere);
+        let changed = history.record(&mut world, Bounds::around(center, radius), |world| {
+            brush.apply(world, center, radius, 1.0)
+        }).unwrap();
+        assert!(world.density(8, 4, 3) < 0.0);
+
+        // The chunks are in meters
+        let expected = vec![(0, 0, 0), (4, 0, 0)];
+        assert_eq!(mark_dirty(&mut chunks, &changed, size, padding), expected);
+
+        let undone = history.undo(&mut world).unwrap();
+        assert_eq!(undone, changed);
+        assert_eq!(world.density(8, 4, 3), 0.0);
+        assert_eq!(mark_dirty(&mut chunks, &undone, size, padding), expected);
+
+        let redone = history.redo(&mut world).unwrap();
+        assert_eq!(redone, changed);
+        assert!(world.density(8, 4, 3) < 0.0);
+        assert_eq!(mark_dirty(&mut chunks, &redone, size, padding), expected);
+    }
+}
@@ -0,0 +1,323 @@
+
+// Dual contouring, from "Dual Contouring of Hermite Data" by Ju et al.
+// Like SurfNet there's a vertex per cell crossed by the surface, but it's
+// placed by minimizing the distance to the tangent planes at the edge
+// crossings (a quadratic error function), so sharp edges and corners are
+// kept instead of rounded.
+
+use mesher::{Mesher, calculate_normals};
+use voxel_source::{VoxelSource, Region};
+use cgmath::{Vector3, InnerSpace};
+use mesh::{Mesh, Vertex};
+
+pub struct DualContouring {
+    pub size: i32,
+}
+
+// The 8 voxels around a cell
+const CORNERS: [[i32; 3]; 8] = [
+    [0, 0, 0], [1, 0, 0], [0, 1, 0], [1, 1, 0],
+    [0, 0, 1], [1, 0, 1], [0, 1, 1], [1, 1, 1],
+];
+
+// The 12 edges of a cell, as pairs of corners
+const EDGES: [[usize; 2]; 12] = [
+    [0, 1], [2, 3], [4, 5], [6, 7],
+    [0, 2], [1, 3], [4, 6], [5, 7],
+    [0, 4], [1, 5], [2, 6], [3, 7],
+];
+
+// Eigenvalues smaller than this, relative to the biggest one, are ignored
+// when solving. Those are the directions in which the vertex can move
+// freely, like along a crease.
+const SINGULAR: f32 = 0.1;
+
+type Matrix = [[f32; 3]; 3];
+
+// Eigen decomposition of a symmetric matrix with Jacobi rotations.
+// Returns the eigenvalues and the eigenvectors as columns.
+fn eigen (mut a: Matrix) -> ([f32; 3], Matrix) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    for _ in 0 .. 8 {
+        for &(p, q) in [(0, 1), (0, 2), (1, 2)].iter() {
+            if a[p][q].abs() < 1e-9 { continue; }
+
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta*theta + 1.0).sqrt());
+            let c = 1.0 / (t*t + 1.0).sqrt();
+            let s = t * c;
+
+            // a = Jt * a * J
+            for row in a.iter_mut() {
+                let (akp, akq) = (row[p], row[q]);
+                row[p] = c*akp - s*akq;
+                row[q] = s*akp + c*akq;
+            }
+            let (ap, aq) = (a[p], a[q]);
+            for (k, (&apk, &aqk)) in ap.iter().zip(aq.iter()).enumerate() {
+                a[p][k] = c*apk - s*aqk;
+                a[q][k] = s*apk + c*aqk;
+            }
+            for row in v.iter_mut() {
+                let (vkp, vkq) = (row[p], row[q]);
+                row[p] = c*vkp - s*vkq;
+                row[q] = s*vkp + c*vkq;
+            }
+        }
+    }
+
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+// Quadratic error function, the sum of squared distances to a set of planes
+struct Qef {
+    ata: Matrix,
+    atb: [f32; 3],
+    mass: Vector3<f32>,
+    count: u32,
+}
+
+impl Qef {
+    fn new () -> Self {
+        Qef {
+            ata: [[0.0; 3]; 3],
+            atb: [0.0; 3],
+            mass: Vector3::new(0.0, 0.0, 0.0),
+            count: 0,
+        }
+    }
+
+    fn add (&mut self, p: Vector3<f32>, n: Vector3<f32>) {
+        let n = [n.x, n.y, n.z];
+        let d = n[0]*p.x + n[1]*p.y + n[2]*p.z;
+        for i in 0 .. 3 {
+            for j in 0 .. 3 {
+                self.ata[i][j] += n[i]*n[j];
+            }
+            self.atb[i] += n[i]*d;
+        }
+        self.mass += p;
+        self.count += 1;
+    }
+
+    // The point that minimizes the error, closest to the average of the
+    // crossings when there are many
+    fn solve (&self) -> Vector3<f32> {
+        let mass = self.mass / self.count as f32;
+        let m = [mass.x, mass.y, mass.z];
+
+        // Solve around the mass point, so the free directions stay there
+        let mut b = [0.0; 3];
+        for (i, b) in b.iter_mut().enumerate() {
+            *b = self.atb[i] - (0 .. 3).map(|j| self.ata[i][j]*m[j]).sum::<f32>();
+        }
+
+        let (values, v) = eigen(self.ata);
+        let max = values.iter().fold(0.0f32, |a, b| a.max(b.abs()));
+
+        // Pseudo inverse, x = V * D^-1 * Vt * b
+        let mut x = [0.0; 3];
+        for k in 0 .. 3 {
+            if max == 0.0 || values[k].abs() < max * SINGULAR { continue; }
+            let vtb: f32 = (0 .. 3).map(|i| v[i][k]*b[i]).sum();
+            for i in 0 .. 3 {
+                x[i] += v[i][k] * vtb / values[k];
+            }
+        }
+
+        mass + Vector3::new(x[0], x[1], x[2])
+    }
+}
+
+pub struct Builder<'a> {
+    size: i32,
+    source: &'a dyn VoxelSource,
+    // From -1, with an extra voxel at each side for the gradients
+    region: Region,
+    mesh: Mesh,
+    indexmap: Vec<i32>,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new (size: i32, source: &'a dyn VoxelSource) -> Self {
+        let rs = size + 3;
+        let mut region = Region::new([-1, -1, -1], [rs, rs, rs], 1);
+        source.fill_region(&mut region);
+        Builder {
+            size,
+            source,
+            region,
+            mesh: Mesh::new(),
+            indexmap: vec![-1; (size*size*size) as usize],
+        }
+    }
+
+    fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+        self.region.density(x+1, y+1, z+1)
+    }
+
+    // Points towards where the density grows, into the solid
+    fn gradient (&self, x: i32, y: i32, z: i32) -> Vector3<f32> {
+        Vector3::new(
+            self.density(x+1, y, z) - self.density(x-1, y, z),
+            self.density(x, y+1, z) - self.density(x, y-1, z),
+            self.density(x, y, z+1) - self.density(x, y, z-1)
+        )
+    }
+
+    fn index_at(&self, x: i32, y: i32, z: i32) -> i32 {
+        let sz = self.size;
+        if x >= 0 && y >= 0 && z >= 0 && x < sz && y < sz && z < sz {
+            let i = x + y*sz + z*sz*sz;
+            self.indexmap[i as usize]
+        } else { -1 }
+    }
+
+    fn index_at_off(&self, x: i32, y: i32, z: i32, off: [i32;3]) -> i32 {
+        self.index_at(x+off[0], y+off[1], z+off[2])
+    }
+
+    fn create_vertex (&mut self, x: i32, y: i32, z: i32) {
+        let mut densities = [0.0; 8];
+        let mut count = 0;
+        let mut material = 0;
+        let mut closest = f32::MAX;
+
+        for i in 0 .. 8 {
+            let c = CORNERS[i];
+            let voxel = self.region.get(x+c[0]+1, y+c[1]+1, z+c[2]+1);
+            densities[i] = voxel.density;
+            if voxel.is_solid() {
+                count += 1;
+                // The material of the solid voxel closest to the surface
+                if voxel.density < closest {
+                    closest = voxel.density;
+                    material = voxel.material;
+                }
+            }
+        }
+
+        if count == 0 || count == 8 { return; }
+
+        let mut qef = Qef::new();
+        for edge in EDGES.iter() {
+            let (a, b) = (edge[0], edge[1]);
+            let (da, db) = (densities[a], densities[b]);
+            if (da > 0.0) == (db > 0.0) { continue; }
+
+            let (ca, cb) = (CORNERS[a], CORNERS[b]);
+            let pa = Vector3::new((x+ca[0]) as f32, (y+ca[1]) as f32, (z+ca[2]) as f32);
+            let pb = Vector3::new((x+cb[0]) as f32, (y+cb[1]) as f32, (z+cb[2]) as f32);
+            let t = da / (da - db);
+            let p = pa + (pb - pa) * t;
+
+            let n = match self.source.normal([p.x, p.y, p.z]) {
+                Some(n) => Vector3::new(n[0], n[1], n[2]),
+                None => {
+                    let ga = self.gradient(x+ca[0], y+ca[1], z+ca[2]);
+                    let gb = self.gradient(x+cb[0], y+cb[1], z+cb[2]);
+                    let g = ga + (gb - ga) * t;
+
+                    // Flat densities don't have a direction, the crossing
+                    // still counts for the mass point
+                    if g.magnitude2() > 0.0 { -g.normalize() } else { g }
+                }
+            };
+            qef.add(p, n);
+        }
+
+        let cell = Vector3::new(x as f32, y as f32, z as f32);
+        let mut pos = qef.solve();
+
+        // Vertices outside of their cell make folded triangles
+        let inside = |v: f32, c: f32| v >= c && v <= c + 1.0;
+        if !(inside(pos.x, cell.x) && inside(pos.y, cell.y) && inside(pos.z, cell.z)) {
+            pos = qef.mass / qef.count as f32;
+        }
+
+        let ix = (x + y*self.size + z*self.size*self.size) as usize;
+        self.indexmap[ix] = self.mesh.vertices.len() as i32;
+        self.mesh.vertices.push(Vertex::with_material(pos, material));
+    }
+
+    fn connect_faces (
+            &mut self,
+            x: i32, y: i32, z: i32,
+            offs: [[i32; 3]; 4] ) {
+
+        let mut ix = [0; 4];
+        for i in 0 .. 4 {
+            let index = self.index_at_off(x, y, z, offs[i]);
+            if index < 0 { return; }
+            ix[i] = index as u32;
+        }
+
+        let pos = self.density(x+1, y+1, z+1) > 0.0;
+        let p = offs[3];
+        let neg = self.density(x+p[0], y+p[1], z+p[2]) > 0.0;
+
+        if pos != neg {
+            let o = if neg {[0,1,2, 2,1,3]} else {[2,1,0, 3,1,2]};
+            for i in 0..6 {
+                self.mesh.indices.push(ix[o[i]]);
+            }
+        }
+    }
+
+    fn build (&mut self) {
+        for x in 0 .. self.size {
+            for y in 0 .. self.size {
+                for z in 0 .. self.size {
+                    self.create_vertex(x, y, z);
+                }
+            }
+        }
+
+        for x in 0 .. self.size-1 {
+            for y in 0 .. self.size-1 {
+                for z in 0 .. self.size-1 {
+                    self.connect_faces(x, y, z, [
+                        [0, 0, 0],
+                        [0, 1, 0],
+                        [0, 0, 1],
+                        [0, 1, 1],
+                    ]);
+                    self.connect_faces(x, y, z, [
+                        [0, 0, 0],
+                        [0, 0, 1],
+                        [1, 0, 0],
+                        [1, 0, 1],
+                    ]);
+                    self.connect_faces(x, y, z, [
+                        [0, 0, 0],
+                        [1, 0, 0],
+                        [0, 1, 0],
+                        [1, 1, 0],
+                    ]);
+                }
+            }
+        }
+
+        calculate_normals(&mut self.mesh);
+    }
+}
+
+impl Mesher for DualContouring {
+    fn mesh (&mut self, source: &dyn VoxelSource) -> Mesh {
+
+        println!("Contouring the duals...");
+        let now = ::std::time::Instant::now();
+
+        let mut builder = Builder::new(self.size, source);
+        builder.build();
+
+        let tm = now.elapsed();
+        println!("The duals contoured in {} ms",
+            (tm.as_secs()*1000) + tm.subsec_millis() as u64);
+
+        builder.mesh
+    }
+
+    fn size (&self) -> i32 { self.size }
+}
@@ -0,0 +1,250 @@
+
+use voxel_source::{VoxelSource, EditSource, Voxel, Bounds};
+
+// The edits of every voxel in a box, None where it came from the source
+struct Snapshot {
+    bounds: Bounds,
+    voxels: Vec<Option<Voxel>>,
+}
+
+impl Snapshot {
+    fn take<S: VoxelSource> (world: &EditSource<S>, bounds: Bounds) -> Self {
+        let mut voxels = vec![];
+        for z in bounds.min[2] .. bounds.max[2]+1 {
+            for y in bounds.min[1] .. bounds.max[1]+1 {
+                for x in bounds.min[0] .. bounds.max[0]+1 {
+                    voxels.push(world.edit(x, y, z));
+                }
+            }
+        }
+        Snapshot { bounds, voxels }
+    }
+
+    // The part of the snapshot inside of the bounds, which must be inside
+    // of the snapshot's bounds
+    fn crop (&self, bounds: Bounds) -> Self {
+        let min = self.bounds.min;
+        let sx = self.bounds.max[0] - min[0] + 1;
+        let sy = self.bounds.max[1] - min[1] + 1;
+
+        let mut voxels = vec![];
+        for z in bounds.min[2] .. bounds.max[2]+1 {
+            for y in bounds.min[1] .. bounds.max[1]+1 {
+                for x in bounds.min[0] .. bounds.max[0]+1 {
+                    let i = (x-min[0]) + (y-min[1])*sx + (z-min[2])*sx*sy;
+                    voxels.push(self.voxels[i as usize]);
+                }
+            }
+        }
+        Snapshot { bounds, voxels }
+    }
+
+    fn restore<S: VoxelSource> (&self, world: &mut EditSource<S>) {
+        let b = self.bounds;
+        let mut i = 0;
+        for z in b.min[2] .. b.max[2]+1 {
+            for y in b.min[1] .. b.max[1]+1 {
+                for x in b.min[0] .. b.max[0]+1 {
+                    world.restore(x, y, z, self.voxels[i]);
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+struct Change {
+    before: Snapshot,
+    after: Snapshot,
+}
+
+type Transaction = Vec<Change>;
+
+// Undo and redo for the edit layer. Every edit goes through record, which
+// saves what was in the area before and after it. Edits made between begin
+// and commit are undone and redone together.
+pub struct History {
+    undo: Vec<Transaction>,
+    redo: Vec<Transaction>,
+    open: Transaction,
+    // begin and commit can be nested, only the outer ones count
+    depth: u32,
+}
+
+fn union (a: Option<Bounds>, b: Bounds) -> Option<Bounds> {
+    Some(match a {
+        Some(a) => a.union(&b),
+        None => b,
+    })
+}
+
+impl History {
+    pub fn new () -> Self {
+        History { undo: vec![], redo: vec![], open: vec![], depth: 0 }
+    }
+
+    pub fn begin (&mut self) {
+        self.depth += 1;
+    }
+
+    pub fn commit (&mut self) {
+        if self.depth == 0 { return; }
+        self.depth -= 1;
+        if self.depth == 0 {
+            self.close();
+        }
+    }
+
+    // The open edits become one step of the undo
+    fn close (&mut self) {
+        if !self.open.is_empty() {
+            self.undo.push(::std::mem::take(&mut self.open));
+        }
+    }
+
+    // Applies an edit that only changes voxels inside of the bounds, and
+    // returns what it returns, the voxels that actually changed.
+    pub fn record<S: VoxelSource, F> (
+            &mut self,
+            world: &mut EditSource<S>,
+            bounds: Bounds,
+            edit: F
+        ) -> Option<Bounds>
+        where F: FnOnce(&mut EditSource<S>) -> Option<Bounds> {
+
+        let before = Snapshot::take(world, bounds);
+        let changed = edit(world)?;
+
+        self.redo.clear();
+        self.open.push(Change {
+            before: before.crop(changed),
+            after: Snapshot::take(world, changed),
+        });
+
+        // Not in a transaction, it's undone alone
+        if self.depth == 0 {
+            self.close();
+        }
+
+        Some(changed)
+    }
+
+    // Returns the voxels that changed, None if there was nothing to undo
+    pub fn undo<S: VoxelSource> (&mut self, world: &mut EditSource<S>) -> Option<Bounds> {
+        // Undoing in the middle of a transaction closes it first
+        if !self.open.is_empty() {
+            self.close();
+            self.depth = 0;
+        }
+
+        let transaction = self.undo.pop()?;
+
+        let mut bounds = None;
+        for change in transaction.iter().rev() {
+            change.before.restore(world);
+            bounds = union(bounds, change.before.bounds);
+        }
+
+        self.redo.push(transaction);
+        bounds
+    }
+
+    pub fn redo<S: VoxelSource> (&mut self, world: &mut EditSource<S>) -> Option<Bounds> {
+        let transaction = self.redo.pop()?;
+
+        let mut bounds = None;
+        for change in transaction.iter() {
+            change.after.restore(world);
+            bounds = union(bounds, change.after.bounds);
+        }
+
+        self.undo.push(transaction);
+        bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brush::{Brush, Shape};
+    use voxel_source::SineSource;
+
+    const CENTER: [f32; 3] = [5.3, 0.4, 4.7];
+    const RADIUS: f32 = 3.0;
+
+    // Flat ground at 0
+    fn world () -> EditSource<SineSource> {
+        EditSource::new(SineSource { amplitude: 0.0, magnitude: 0.0, bias: 0.0 })
+    }
+
+    // All the densities the brushes can reach
+    fn densities<S: VoxelSource> (world: &EditSource<S>) -> Vec<f32> {
+        let b = Bounds::around(CENTER, RADIUS + 2.0);
+        let mut list = vec![];
+        for z in b.min[2] .. b.max[2]+1 {
+            for y in b.min[1] .. b.max[1]+1 {
+                for x in b.min[0] .. b.max[0]+1 {
+                    list.push(world.density(x, y, z));
+                }
+            }
+        }
+        list
+    }
+
+    fn stroke<S: VoxelSource> (
+            history: &mut History,
+            world: &mut EditSource<S>,
+            brush: Brush,
+            center: [f32; 3]
+        ) -> Option<Bounds> {
+        history.record(world, Bounds::around(center, RADIUS), |world| {
+            brush.apply(world, center, RADIUS, 1.0)
+        })
+    }
+
+    #[test]
+    fn undo_and_redo_a_brush () {
+        let (mut world, mut history) = (world(), History::new());
+        let before = densities(&world);
+
+        let changed = stroke(&mut history, &mut world, Brush::Remove(Shape::Sphere), CENTER).unwrap();
+        let after = densities(&world);
+        assert!(world.density(5, -1, 5) < 0.0);
+        let area = Bounds::around(CENTER, RADIUS);
+        assert_eq!(area.union(&changed), area);
+
+        assert_eq!(history.undo(&mut world), Some(changed));
+        assert_eq!(densities(&world), before);
+        // Back to the source, not an edit with the same density
+        assert!(world.edit(5, -1, 5).is_none());
+
+        assert_eq!(history.redo(&mut world), Some(changed));
+        assert_eq!(densities(&world), after);
+        assert_eq!(history.redo(&mut world), None);
+
+        // A new edit forgets what was undone
+        history.undo(&mut world);
+        stroke(&mut history, &mut world, Brush::Add(Shape::Cube, 1), CENTER).unwrap();
+        assert_eq!(history.redo(&mut world), None);
+    }
+
+    #[test]
+    fn undo_a_transaction_at_once () {
+        let (mut world, mut history) = (world(), History::new());
+        let before = densities(&world);
+        let other = [CENTER[0] + 2.0, CENTER[1], CENTER[2] - 1.0];
+
+        history.begin();
+        let a = stroke(&mut history, &mut world, Brush::Remove(Shape::Sphere), CENTER).unwrap();
+        let b = stroke(&mut history, &mut world, Brush::Add(Shape::Cube, 1), other).unwrap();
+        history.commit();
+        let after = densities(&world);
+
+        assert_eq!(history.undo(&mut world), Some(a.union(&b)));
+        assert_eq!(densities(&world), before);
+        assert_eq!(history.undo(&mut world), None);
+
+        assert_eq!(history.redo(&mut world), Some(a.union(&b)));
+        assert_eq!(densities(&world), after);
+    }
+}
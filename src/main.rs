@@ -12,20 +12,30 @@ mod mesher;
 mod surfnet;
 mod blocky;
 mod marching_cubes;
+mod dual_contouring;
 mod mesh;
 mod chunk;
+mod brush;
+mod history;
 mod base;
 
 use base::Base;
 use camera::Camera;
 
-use voxel_source::SineSource;
+use voxel_source::{VoxelSource, TerrainSource, CaveSource, SineSource, SphereSource, Solid, Voxel, GRASS, SOILSAND};
+use voxel_source::{Union, Intersection, Subtract, Translate, Scale, Rotate, Axis, Repeat};
+use voxel_source::{HeightmapSource, Wrap};
+use voxel_source::sdf::{Sphere, Cuboid, RoundedCuboid, Capsule, Cylinder, Torus, Plane, Vector};
+use voxel_source::sdf::{SmoothUnion, SmoothIntersection, SmoothSubtract};
 use surfnet::SurfNet;
 use blocky::Blocky;
 use mesher::Mesher;
 
-use marching_cubes::MarchingCubes;
+use marching_cubes::{MarchingCubes, MarchingTetrahedra, Smoothing};
+use dual_contouring::DualContouring;
 use chunk::ChunkManager;
+use brush::{Brush, Shape};
+use mesh::Simplify;
 
 use gfx::traits::FactoryExt;
 use gfx::Device;
@@ -37,17 +47,105 @@ pub struct World {
     sun_angle: Vector3<f32>,
 }
 
+// The example scene of the combinators and the distance fields: sine hills
+// with a crater, a floating island, a row of arches, a tunnel and a few
+// smooth shapes
+fn csg_scene () -> impl VoxelSource {
+    let hills = Subtract(
+        SineSource{amplitude: 0.01, magnitude: 15.0, bias: 20.0},
+        SphereSource{x: 64, y: 24, z: 64, r: 24}
+    );
+
+    // A ball with the top cut flat, twice as big. The cut only needs to
+    // know what is under it, so it doesn't need densities.
+    let island = Scale {
+        source: Intersection(
+            SphereSource{x: 0, y: 0, z: 0, r: 6},
+            Solid(SineSource{amplitude: 0.0, magnitude: 0.0, bias: 2.0})
+        ),
+        factor: 2.0,
+    };
+
+    // Rings standing up, one every 48 voxels
+    let ring = || Torus{center: Vector::new(0.0, 0.0, 0.0), major: 10.0, minor: 2.5, material: SOILSAND};
+    let arches = Repeat {
+        source: Translate {
+            source: Rotate{source: ring(), axis: Axis::X, turns: 1},
+            x: 24, y: 20, z: 160,
+        },
+        x: 48, y: 0, z: 0,
+    };
+
+    // A ribbed tunnel, with rings every 12 voxels. It's made along x and
+    // turned to go along z.
+    let tunnel = Rotate {
+        source: Repeat {
+            source: Translate {
+                source: Rotate{source: ring(), axis: Axis::Z, turns: 1},
+                x: 6, y: 0, z: 0,
+            },
+            x: 12, y: 0, z: 0,
+        },
+        axis: Axis::Y,
+        turns: 1,
+    };
+
+    // The distance fields, blended where they touch. A tower with a dome,
+    // a gate, a die and a ramp.
+    let tower = SmoothUnion(
+        Cylinder{center: Vector::new(48.0, 30.0, 96.0), radius: 5.0, half_height: 20.0, material: SOILSAND},
+        Sphere{center: Vector::new(48.0, 50.0, 96.0), radius: 8.0, material: GRASS},
+        3.0
+    );
+    let gate = SmoothSubtract(
+        RoundedCuboid{center: Vector::new(112.0, 30.0, 112.0), half: Vector::new(4.0, 14.0, 16.0), radius: 2.0, material: SOILSAND},
+        Capsule{a: Vector::new(100.0, 24.0, 112.0), b: Vector::new(124.0, 24.0, 112.0), radius: 7.0, material: SOILSAND},
+        2.0
+    );
+    let die = SmoothIntersection(
+        Cuboid{center: Vector::new(160.0, 40.0, 64.0), half: Vector::new(6.0, 6.0, 6.0), material: GRASS},
+        Sphere{center: Vector::new(160.0, 40.0, 64.0), radius: 8.0, material: GRASS},
+        1.0
+    );
+    let ramp = Intersection(
+        Cuboid{center: Vector::new(64.0, 30.0, 32.0), half: Vector::new(16.0, 12.0, 6.0), material: SOILSAND},
+        Plane::new(Vector::new(-1.0, 2.0, 0.0), 0.0, SOILSAND).unwrap()
+    );
+
+    Union(
+        Union(
+            Union(hills, Translate{source: island, x: 96, y: 64, z: 32}),
+            Union(arches, Translate{source: tunnel, x: 200, y: 30, z: 0})
+        ),
+        Union(Union(tower, gate), Union(die, ramp))
+    )
+}
+
+// Terrain from the heightmap the artists export, repeating. The material
+// map is optional.
+fn heightmap_scene () -> ::image::ImageResult<HeightmapSource> {
+    let mut source = HeightmapSource::open("assets/heightmap.png")?;
+    source.horizontal_scale = 2.0;
+    source.wrap = Wrap::Tile;
+    if source.load_materials("assets/materials.png").is_err() {
+        println!("There is no material map, all the terrain is grass");
+    }
+    Ok(source)
+}
+
 pub fn main() {
 
     let mut base = Base::new("Miterra", 500, 500);
 
-    let source = SineSource{amplitude: 0.01, magnitude: 15.0, bias: 20.0};
+    let source = CaveSource::new(TerrainSource::new(1), 1);
+    //let source = SineSource{amplitude: 0.01, magnitude: 15.0, bias: 20.0};
     //let source = SphereSource{x: 32, y: -64, z: 32, r: 96};
 
-    //let mesher = Blocky{size: 64};
+    //let mesher = Blocky{size: 64, greedy: true};
 
     // The chunk size in voxels doubles the real size, because voxels are half a meter big
-    let mesher = MarchingCubes{size: 32, smooth: true};
+    // The source has real densities, so it doesn't need to be blurred
+    let mesher = MarchingCubes{size: 32, smoothing: Smoothing::None, manifold: false};
 
     let mut chunks = ChunkManager::new(source, mesher, &mut base);
 
@@ -66,6 +164,11 @@ pub fn main() {
         chunks.generate(s, 0, s, n);
     }
 
+    // The far chunks don't need all the triangles. The error is in their
+    // voxels, which are bigger the farther they are.
+    chunks.set_simplify(2, Some(Simplify { triangles: 0, error: 0.02 }));
+    chunks.set_simplify(4, Some(Simplify { triangles: 0, error: 0.05 }));
+
     // Rango aceptable de FOV: 45° - 120°
     // Mejor FOV: 100°
     let mut cam = Camera::new(45.0, 0.01, 500.0);
@@ -76,6 +179,12 @@ pub fn main() {
     cam.pitch = Rad::from(Deg(30.0));
     cam.sensitivity = 4.0;
 
+    // The brush sculpts this many meters in front of the camera
+    // while the right button is held, each stroke is undone at once
+    let mut brush = Brush::Remove(Shape::Sphere);
+    let mut sculpting = false;
+    const BRUSH_DISTANCE: f32 = 6.0;
+
     let mut running = true;
     let mut needs_update = false;
 
@@ -98,6 +207,18 @@ pub fn main() {
     println!("- Press 1 to mine the craft.");
     println!("- Press 2 to net the surface.");
     println!("- Press 3 to march the cubes.");
+    println!("- Press 4 to contour the duals.");
+    println!("- Press 5 to march the tetrahedra.");
+    println!("- Press 6 to march the cubes without holes.");
+    println!("- Press 7 to net the surface from the densities.");
+    println!("- Press 8 or 9 to march the cubes, smoothed with a box or a gaussian blur.");
+    println!("- Press O, P or G to export the world to world.obj, world.ply or world.glb.");
+    println!("- Press M to check the meshes of the chunks for broken triangles.");
+    println!("- Press C to see the CSG example scene, and T to go back to the terrain.");
+    println!("- Press H to load the terrain from assets/heightmap.png.");
+    println!("- Hold the right button to sculpt. Press R to dig, F to build, E to smooth,");
+    println!("  Q to flatten or V to paint, and Z or X to undo or redo.");
+    println!("- Press B to place one voxel where the brush is.");
 
     while running {
         match base {
@@ -136,6 +257,19 @@ pub fn main() {
                                 };
                                 try_center_mouse(&window, &mut mouse_pos, center);
                             },
+                            MouseInput{state, button: MouseButton::Right, ..} => {
+                                match state {
+                                    ElementState::Pressed if active && !sculpting => {
+                                        chunks.begin_transaction();
+                                        sculpting = true;
+                                    },
+                                    ElementState::Released if sculpting => {
+                                        chunks.commit_transaction();
+                                        sculpting = false;
+                                    },
+                                    _ => ()
+                                }
+                            },
                             KeyboardInput{
                                 input: ::glutin::KeyboardInput {
                                     state, virtual_keycode: Some(key), ..
@@ -166,9 +300,51 @@ pub fn main() {
                                 } }
 
                                 if active && pressed { match key {
-                                    Key::Key1 => chunks.set_mesher(Blocky{size: 32}),
-                                    Key::Key2 => chunks.set_mesher(SurfNet{size: 32, smooth: 7}), // smooth 7 is best
-                                    Key::Key3 => chunks.set_mesher(MarchingCubes{size: 32, smooth: true}),
+                                    Key::Key1 => chunks.set_mesher(Blocky{size: 32, greedy: true}),
+                                    Key::Key2 => chunks.set_mesher(SurfNet{size: 32, smooth: 7, density: false}), // smooth 7 is best
+                                    Key::Key3 => chunks.set_mesher(MarchingCubes{size: 32, smoothing: Smoothing::None, manifold: false}),
+                                    Key::Key4 => chunks.set_mesher(DualContouring{size: 32}),
+                                    Key::Key5 => chunks.set_mesher(MarchingTetrahedra{size: 32, smoothing: Smoothing::None}),
+                                    Key::Key6 => chunks.set_mesher(MarchingCubes{size: 32, smoothing: Smoothing::None, manifold: true}),
+                                    Key::Key7 => chunks.set_mesher(SurfNet{size: 32, smooth: 0, density: true}),
+                                    Key::Key8 => chunks.set_mesher(MarchingCubes{size: 32, smoothing: Smoothing::Box(2), manifold: false}),
+                                    Key::Key9 => chunks.set_mesher(MarchingCubes{size: 32, smoothing: Smoothing::Gaussian(3), manifold: false}),
+                                    Key::R => brush = Brush::Remove(Shape::Sphere),
+                                    Key::F => brush = Brush::Add(Shape::Cube, SOILSAND),
+                                    Key::E => brush = Brush::Smooth,
+                                    Key::Q => brush = Brush::Flatten([0.0, 1.0, 0.0]),
+                                    Key::V => brush = Brush::Paint(SOILSAND),
+                                    Key::B => {
+                                        let p = (cam.pos + cam.forward() * BRUSH_DISTANCE) * 2.0;
+                                        let voxel = Voxel{density: 1.0, material: SOILSAND};
+                                        chunks.set(p.x.round() as i32, p.y.round() as i32, p.z.round() as i32, voxel);
+                                    },
+                                    Key::Z => { chunks.undo(); },
+                                    Key::X => { chunks.redo(); },
+                                    Key::C => chunks.set_source(csg_scene()),
+                                    Key::T => chunks.set_source(CaveSource::new(TerrainSource::new(1), 1)),
+                                    Key::H => match heightmap_scene() {
+                                        Ok(source) => chunks.set_source(source),
+                                        Err(e) => println!("Could not load the heightmap: {}", e),
+                                    },
+                                    Key::M => {
+                                        let reports = chunks.validate();
+                                        for &((x, y, z), ref report) in reports.iter() {
+                                            println!("The chunk at {}, {}, {} has {}", x, y, z, report);
+                                        }
+                                        println!("{} chunks with broken meshes", reports.len());
+                                    },
+                                    Key::O | Key::P | Key::G => {
+                                        let path = match key {
+                                            Key::O => "world.obj",
+                                            Key::P => "world.ply",
+                                            _ => "world.glb",
+                                        };
+                                        match chunks.export(::std::path::Path::new(path)) {
+                                            Ok(_) => println!("Exported the world to {}", path),
+                                            Err(e) => println!("Could not export the world: {}", e),
+                                        }
+                                    },
                                     _ => {}
                                 } }
                             }
@@ -179,7 +355,17 @@ pub fn main() {
             }
         }
 
-        chunks.update(&mut base);
+        if sculpting {
+            // Voxels are half a meter big
+            let p = (cam.pos + cam.forward() * BRUSH_DISTANCE) * 2.0;
+            chunks.apply_brush(&brush, [p.x, p.y, p.z], 4.0, 0.3);
+        }
+
+        if chunks.update(&mut base) {
+            if let Some(report) = chunks.cache_report() {
+                println!("Optimized the chunk meshes, {}", report);
+            }
+        }
 
         cam.update();
         base.update_world(base::World {
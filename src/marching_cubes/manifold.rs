@@ -0,0 +1,321 @@
+
+// Cells without ambiguities, in the spirit of Marching Cubes 33 (Chernyaev).
+// Instead of the tables, the surface of a cell is built from it's contours:
+//
+// - Each face is solved alone with the asymptotic decider, so the two cells
+//   that share a face always agree, and there are no holes.
+// - The contours of the faces are joined into loops around the cell, and
+//   each loop is closed with a fan.
+// - If there are two loops, around two separate parts of one material and
+//   a single part of the other, and the trilinear interpolation connects
+//   the two parts through the inside of the cell, they are joined with a
+//   tube instead, like the tunnel of case 4 of MC33. Any other interior
+//   ambiguity, like those of cells with three or more loops, is not
+//   checked, they get only fans, so the inside of those cells may not
+//   follow the interpolation.
+//
+// Every edge of the cell that is crossed belongs to one loop, and the new
+// vertices stay inside of the cell, so the result is always manifold.
+
+use cgmath::{Vector3, Matrix3, InnerSpace, SquareMatrix};
+
+use super::Builder;
+use super::data::{TARGET, EDGE_CONNECTION};
+
+// The faces of the cell, counter clockwise seen from outside
+const FACES: [[usize; 4]; 6] = [
+    [0, 1, 2, 3], [4, 7, 6, 5],
+    [0, 4, 5, 1], [3, 2, 6, 7],
+    [0, 3, 7, 4], [1, 5, 6, 2],
+];
+
+fn edge_between (a: usize, b: usize) -> usize {
+    EDGE_CONNECTION.iter().position(|e| (e[0] == a && e[1] == b) || (e[0] == b && e[1] == a)).unwrap()
+}
+
+// The trilinear interpolation of the cell, f = a + bx + cy + dz + exy + fyz + gzx + hxyz
+struct Trilinear { c: [f32; 8] }
+
+impl Trilinear {
+    fn new (cube: [f32; 8]) -> Self {
+        // Corners by position, see VERTEX_OFFSET
+        let (v000, v100, v101, v001) = (cube[0], cube[1], cube[2], cube[3]);
+        let (v010, v110, v111, v011) = (cube[4], cube[5], cube[6], cube[7]);
+        Trilinear { c: [
+            v000,
+            v100 - v000,
+            v010 - v000,
+            v001 - v000,
+            v110 - v100 - v010 + v000,
+            v011 - v010 - v001 + v000,
+            v101 - v100 - v001 + v000,
+            v111 - v110 - v101 - v011 + v100 + v010 + v001 - v000,
+        ] }
+    }
+
+    fn value (&self, p: Vector3<f32>) -> f32 {
+        let [a, b, c, d, e, f, g, h] = self.c;
+        a + b*p.x + c*p.y + d*p.z + e*p.x*p.y + f*p.y*p.z + g*p.z*p.x + h*p.x*p.y*p.z
+    }
+
+    fn gradient (&self, p: Vector3<f32>) -> Vector3<f32> {
+        let [_, b, c, d, e, f, g, h] = self.c;
+        Vector3::new(
+            b + e*p.y + g*p.z + h*p.y*p.z,
+            c + e*p.x + f*p.z + h*p.x*p.z,
+            d + f*p.y + g*p.x + h*p.x*p.y
+        )
+    }
+
+    // The points where the gradient is zero, inside of the cell. Shifting
+    // the origin, the equations are h·YZ = A, h·XZ = B and h·XY = C.
+    // Without h they are linear, with one solution at most.
+    fn saddles (&self) -> Vec<Vector3<f32>> {
+        let [_, b, c, d, e, f, g, h] = self.c;
+        let inside = |p: &Vector3<f32>| [p.x, p.y, p.z].iter().all(|&t| t > 0.0 && t < 1.0);
+        if h.abs() < 1e-6 {
+            let m = Matrix3::new(0.0, e, g, e, 0.0, f, g, f, 0.0);
+            return m.invert().map(|m| m * Vector3::new(-b, -c, -d)).into_iter().filter(inside).collect();
+        }
+
+        let aa = g*e/h - b;
+        let bb = e*f/h - c;
+        let cc = f*g/h - d;
+        if aa == 0.0 || bb == 0.0 || cc == 0.0 { return vec![]; }
+
+        // (XYZ)² = ABC/h³
+        let q = aa*bb*cc / (h*h*h);
+        if q < 0.0 { return vec![]; }
+
+        let mut points = vec![];
+        for &xyz in [q.sqrt(), -q.sqrt()].iter() {
+            let p = Vector3::new(xyz*h/aa - f/h, xyz*h/bb - g/h, xyz*h/cc - e/h);
+            if inside(&p) { points.push(p); }
+        }
+        points
+    }
+}
+
+// Union find over the corners
+fn root (parent: &[usize; 8], i: usize) -> usize {
+    let mut i = i;
+    while parent[i] != i { i = parent[i]; }
+    i
+}
+
+fn join (parent: &mut [usize; 8], a: usize, b: usize) {
+    let (a, b) = (root(parent, a), root(parent, b));
+    parent[a] = b;
+}
+
+impl<'a> Builder<'a> {
+    // The triangles of the cell, with the vertices of the crossed edges
+    pub(super) fn manifold_cube (&mut self, pos: [f32; 3], cube: [f32; 8], edges: &[u32; 12]) {
+        let solid: Vec<bool> = cube.iter().map(|&v| v > TARGET).collect();
+
+        // The contour segments of every face, going around it counter
+        // clockwise, from an edge where it goes from solid to air to one
+        // where it goes back. Each crossed edge starts one segment, in
+        // one of the faces, and ends another, in the other face.
+        let mut next = [12; 12];
+        let mut parent = [0, 1, 2, 3, 4, 5, 6, 7];
+
+        for face in FACES.iter() {
+            let mut outs = vec![];
+            let mut ins = vec![];
+            for i in 0 .. 4 {
+                let (a, b) = (face[i], face[(i+1) % 4]);
+                if solid[a] && !solid[b] { outs.push(i); }
+                if !solid[a] && solid[b] { ins.push(i); }
+                if solid[a] == solid[b] { join(&mut parent, a, b); }
+            }
+
+            // Two opposite solid corners. The asymptotic decider: they are
+            // connected if the bilinear interpolation is solid at it's
+            // saddle point. Written so that the cell at the other side of
+            // the face gets exactly the same result.
+            let saddle = outs.len() == 2;
+            let connected = saddle && {
+                let v: Vec<f32> = face.iter().map(|&c| cube[c]).collect();
+                (v[0]*v[2] - v[1]*v[3]) / ((v[0] + v[2]) - (v[1] + v[3])) > TARGET
+            };
+
+            if saddle {
+                let (a, b) = if connected == solid[face[0]] {
+                    (face[0], face[2])
+                } else {
+                    (face[1], face[3])
+                };
+                join(&mut parent, a, b);
+            }
+
+            // Each segment has the solid at it's left
+            for &out in outs.iter() {
+                let target = if !saddle {
+                    ins[0]
+                } else if connected {
+                    // Around the air corner after it
+                    (out + 1) % 4
+                } else {
+                    // Around the solid corner before it
+                    (out + 3) % 4
+                };
+                let from = edge_between(face[out], face[(out+1) % 4]);
+                let to = edge_between(face[target], face[(target+1) % 4]);
+                next[from] = to;
+            }
+        }
+
+        // Follow the segments into loops
+        let mut loops: Vec<Vec<u32>> = vec![];
+        let mut used = [false; 12];
+        for start in 0 .. 12 {
+            if next[start] == 12 || used[start] { continue; }
+            let mut contour = vec![];
+            let mut e = start;
+            while !used[e] {
+                used[e] = true;
+                contour.push(edges[e]);
+                e = next[e];
+            }
+            loops.push(contour);
+        }
+
+        // Two loops around two separate parts of the same material, that
+        // may be connected inside
+        if loops.len() == 2 {
+            let mut parts = [vec![], vec![]];
+            for (i, &s) in solid.iter().enumerate() {
+                let r = root(&parent, i);
+                let side = s as usize;
+                if !parts[side].contains(&r) { parts[side].push(r); }
+            }
+            for side in 0 .. 2 {
+                if parts[side].len() != 2 || parts[1 - side].len() != 1 { continue; }
+
+                let f = Trilinear::new(cube);
+                let saddle = f.saddles().into_iter().find(|&p| (f.value(p) > TARGET) == (side == 1));
+                if let Some(saddle) = saddle {
+                    self.tube(pos, &f, saddle, &loops[0], &loops[1]);
+                    return;
+                }
+            }
+        }
+
+        for contour in loops.iter() {
+            self.fan(contour);
+        }
+    }
+
+    // The loops go around the solid, the triangles are the other way
+    fn fan (&mut self, contour: &[u32]) {
+        let n = contour.len();
+        let p = |i: usize| self.vertices[contour[i] as usize];
+
+        if n == 3 {
+            self.indices.extend_from_slice(&[contour[0], contour[2], contour[1]]);
+            return;
+        }
+        if n == 4 {
+            // Split by the shorter diagonal
+            let r = if (p(2) - p(0)).magnitude2() <= (p(3) - p(1)).magnitude2() { 0 } else { 1 };
+            let q = |i: usize| contour[(r + i) % 4];
+            self.indices.extend_from_slice(&[q(0), q(2), q(1), q(0), q(3), q(2)]);
+            return;
+        }
+
+        // A vertex in the middle, for when the loop isn't convex
+        let mut center = Vector3::new(0.0, 0.0, 0.0);
+        for i in 0 .. n { center += p(i); }
+        center /= n as f32;
+
+        let c = self.vertices.len() as u32;
+        let material = self.vertex_materials[contour[0] as usize];
+        self.vertices.push(center);
+        self.vertex_materials.push(material);
+
+        for i in 0 .. n {
+            self.indices.extend_from_slice(&[c, contour[(i+1) % n], contour[i]]);
+        }
+    }
+
+    // Joins two loops with a tube. It goes through a ring of new vertices
+    // at the saddle, so none of it's edges can be on a face of the cell,
+    // where the next cell may have them too.
+    fn tube (&mut self, pos: [f32; 3], f: &Trilinear, saddle: Vector3<f32>, a: &[u32], b: &[u32]) {
+        let corner = Vector3::new(pos[0], pos[1], pos[2]);
+
+        // Loop a, half the size, around the saddle. Smaller if that goes
+        // out of the cell, when the saddle is near a face.
+        let mut center = Vector3::new(0.0, 0.0, 0.0);
+        for &i in a.iter() { center += self.vertices[i as usize]; }
+        center /= a.len() as f32;
+
+        let mut scale: f32 = 0.5;
+        for &i in a.iter() {
+            let d = self.vertices[i as usize] - center;
+            for k in 0 .. 3 {
+                if d[k] > 0.0 { scale = scale.min(0.9 * (1.0 - saddle[k]) / d[k]); }
+                if d[k] < 0.0 { scale = scale.min(0.9 * saddle[k] / -d[k]); }
+            }
+        }
+
+        let start = self.vertices.len() as u32;
+        for &i in a.iter() {
+            let v = self.vertices[i as usize];
+            let material = self.vertex_materials[i as usize];
+            self.vertices.push(corner + saddle + (v - center) * scale);
+            self.vertex_materials.push(material);
+        }
+        let ring: Vec<u32> = (0 .. a.len() as u32).map(|i| start + i).collect();
+
+        let mut triangles = vec![];
+        let n = a.len();
+        for i in 0 .. n {
+            let j = (i + 1) % n;
+            triangles.push([a[i], a[j], ring[j]]);
+            triangles.push([a[i], ring[j], ring[i]]);
+        }
+
+        // Then from the ring to loop b, walking both evenly, so that no
+        // vertex gets a fan all around. The loops go opposite ways around
+        // the tube.
+        let p = |builder: &Self, i: u32| builder.vertices[i as usize];
+        let b: Vec<u32> = b.iter().rev().cloned().collect();
+        let m = b.len();
+        let first = (0 .. m).min_by(|&i, &j| {
+            let di = (p(self, b[i]) - p(self, ring[0])).magnitude2();
+            let dj = (p(self, b[j]) - p(self, ring[0])).magnitude2();
+            di.total_cmp(&dj)
+        }).unwrap();
+
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            let (ri, rn) = (ring[i % n], ring[(i+1) % n]);
+            let (bj, bn) = (b[(first + j) % m], b[(first + j + 1) % m]);
+
+            if j == m || (i < n && (i+1)*m <= (j+1)*n) {
+                triangles.push([ri, rn, bj]);
+                i += 1;
+            } else {
+                triangles.push([ri, bn, bj]);
+                j += 1;
+            }
+        }
+
+        // Facing the air, against the gradient
+        let mut facing = 0.0;
+        for t in triangles.iter() {
+            let (pa, pb, pc) = (p(self, t[0]), p(self, t[1]), p(self, t[2]));
+            let center = (pa + pb + pc) / 3.0 - corner;
+            facing += (pb - pa).cross(pc - pa).dot(-f.gradient(center));
+        }
+        for t in triangles.iter() {
+            if facing >= 0.0 {
+                self.indices.extend_from_slice(&[t[0], t[1], t[2]]);
+            } else {
+                self.indices.extend_from_slice(&[t[0], t[2], t[1]]);
+            }
+        }
+    }
+}
@@ -1,32 +1,49 @@
 
 mod data;
+mod transition;
+mod tetrahedra;
+mod manifold;
+mod smoothing;
 
-use mesher::{Mesher, calculate_normals};
+pub use self::tetrahedra::MarchingTetrahedra;
+pub use self::smoothing::Smoothing;
+
+use mesher::{Mesher, Transition};
 use cgmath::{Vector3, InnerSpace};
-use voxel_source::VoxelSource;
+use voxel_source::{VoxelSource, Region};
 use mesh::{Mesh, Vertex};
 
 use self::data::*;
 
-pub struct MarchingCubes { pub size: i32, pub smooth: bool }
+// With manifold, the ambiguous faces and some of the ambiguous insides of
+// the cells are solved from the densities instead of taking the fixed choice
+// of the tables, so the surface is always closed and manifold. It's a bit
+// slower and has more triangles.
+pub struct MarchingCubes { pub size: i32, pub smoothing: Smoothing, pub manifold: bool }
 
 pub struct Builder<'a> {
     size: i32,
     source: &'a VoxelSource,
+    smoothing: Smoothing,
+    // Voxels outside of the chunk at each side, for the smoothing
+    padding: i32,
+    manifold: bool,
     voxels: Vec<f32>,
+    materials: Vec<u8>,
     voxel_normals: Vec<Vector3<f32>>,
 
     vertices: Vec<Vector3<f32>>,
-    indices: Vec<u16>,
+    vertex_materials: Vec<u8>,
+    indices: Vec<u32>,
 
     // Vertex caches, inspired on:
     // http://alphanew.net/index.php?section=articles&site=marchoptim&lang=eng
-    ybuf: Vec<u16>,
-    xbuf: Vec<u16>,
-    zbuf: Vec<u16>,
-    ybuf_next: Vec<u16>,
-    xbuf_top: Vec<u16>,
-    zbuf_top: Vec<u16>,
+    ybuf: Vec<u32>,
+    xbuf: Vec<u32>,
+    zbuf: Vec<u32>,
+    ybuf_next: Vec<u32>,
+    xbuf_top: Vec<u32>,
+    zbuf_top: Vec<u32>,
 }
 
 fn get_offset (v1: f32, v2: f32) -> f32 {
@@ -35,13 +52,18 @@ fn get_offset (v1: f32, v2: f32) -> f32 {
 }
 
 impl<'a> Builder<'a> {
-    fn new (source: &'a VoxelSource, size: i32) -> Self {
+    fn new (source: &'a dyn VoxelSource, size: i32, smoothing: Smoothing) -> Self {
         Builder {
-            size: size,
-            source: source,
+            size,
+            source,
+            smoothing,
+            padding: smoothing.padding(),
+            manifold: false,
             voxels: vec![],
+            materials: vec![],
             voxel_normals: vec![],
             vertices: vec![],
+            vertex_materials: vec![],
             indices: vec![],
 
             ybuf: vec![],
@@ -53,105 +75,86 @@ impl<'a> Builder<'a> {
         }
     }
 
+    // Index of the voxel at the chunk position, which can be in the padding
+    fn index (&self, x: i32, y: i32, z: i32) -> usize {
+        let p = self.padding;
+        let s = self.size + 1 + 2*p;
+        ((x+p) + (y+p)*s + (z+p)*s*s) as usize
+    }
+
     fn get(&self, x: i32, y: i32, z: i32) -> f32 {
-        let s = self.size + 5;
-        self.voxels[(x + y*s + z*s*s) as usize]
+        self.voxels[self.index(x, y, z)]
     }
-    
+
+    fn get_material(&self, x: i32, y: i32, z: i32) -> u8 {
+        self.materials[self.index(x, y, z)]
+    }
+
     fn get_normal (&self, x: i32, y: i32, z: i32) -> Vector3<f32> {
-        let s = self.size + 5;
-        self.voxel_normals[(x + y*s + z*s*s) as usize]
+        self.voxel_normals[self.index(x, y, z)]
     }
 
     fn fill_voxels(&mut self) {
-        let s = self.size + 5;
-        self.voxels = vec![0.0; (s*s*s) as usize];
-
-        for x in 0 .. s {
-            for y in 0 .. s {
-                for z in 0 .. s {
-                    // -2 to account for the bluring
-                    let vox = self.source.get(x-2, y-2, z-2);
-                    let v = if vox {1.0} else {-1.0};
-                    self.voxels[(x + y*s + z*s*s) as usize] = v;
-                }
-            }
-        }
+        let s = self.size + 1 + 2*self.padding;
+        let mut region = Region::new([-self.padding; 3], [s; 3], 1);
+        self.source.fill_region(&mut region);
+
+        // The region has the same layout as the voxels
+        self.voxels = region.voxels.iter().map(|v| v.density).collect();
+        self.materials = region.voxels.iter().map(|v| v.material).collect();
     }
 
-    // Box blur
+    // Each voxel gets the weighted sum of the ones around it in each axis,
+    // with the kernel of the smoothing. It's valid from one voxel before the
+    // chunk to one after, which is what the normals need. The passes of the
+    // later axes read the radius more at each side in their axis, so each
+    // pass covers the whole padding in the axes that aren't blurred yet.
     fn blur (&mut self) {
-        // The voxel just outside the limits also needs to be blured
-        let size = self.size+1;
-        let s = size + 4;
-
-        // Blur X
-        for x in 0 .. size {
-            for y in 0 .. s {
-                for z in 0 .. s {
-                    let mut sum = 0.0;
-                    for i in 0..5 {
-                        sum += self.get(x+i, y, z);
-                    }
-                    self.voxels[(x + y*s + z*s*s) as usize] = sum;
-                }
-            }
-        }
-
-        // Blur Y
-        for x in 0 .. size {
-            for y in 0 .. size {
-                for z in 0 .. s {
-                    let mut sum = 0.0;
-                    for i in 0..5 {
-                        sum += self.get(x, y+i, z);
+        let kernel = self.smoothing.kernel();
+        let r = self.smoothing.radius();
+        if r == 0 { return; }
+        let (p, size) = (self.padding, self.size);
+
+        for axis in 0 .. 3 {
+            let range = |d: usize| if d > axis { (-p, size + p) } else { (-1, size + 1) };
+            let ((x0, x1), (y0, y1), (z0, z1)) = (range(0), range(1), range(2));
+
+            let mut blurred = self.voxels.clone();
+            for z in z0 .. z1+1 {
+                for y in y0 .. y1+1 {
+                    for x in x0 .. x1+1 {
+                        let mut sum = 0.0;
+                        for i in -r .. r+1 {
+                            let mut p = [x, y, z];
+                            p[axis] += i;
+                            sum += self.get(p[0], p[1], p[2]) * kernel[(i + r) as usize];
+                        }
+                        blurred[self.index(x, y, z)] = sum;
                     }
-                    self.voxels[(x + y*s + z*s*s) as usize] = sum;
-                }
-            }
-        }
-
-        // Blur Z
-        for x in 0 .. size {
-            for y in 0 .. size {
-                for z in 0 .. size {
-                    let mut sum = 0.0;
-                    for i in 0..5 {
-                        sum += self.get(x, y, z+i);
-                    }
-                    self.voxels[(x + y*s + z*s*s) as usize] = sum;
                 }
             }
+            self.voxels = blurred;
         }
     }
 
+    // The normal of each voxel is the opposite of the gradient of the
+    // densities, which is centered so that every chunk finds the same normal
+    // for the voxels they share.
     fn calculate_voxel_normals (&mut self) {
-        // The voxel just outside the limits also needs it's normals
-        let size = self.size+2;
-        let s = size + 3;
-
-        self.voxel_normals = vec![Vector3::new(0.0, 0.0, 0.0); (s*s*s) as usize];
-
-        // This calculates the normal of each voxel. If you have a 3d array of data
-        // the normal is the derivitive of the x, y and z axis.
-        // Normally you need to flip the normal (*-1) (but maybe it is not needed in this case?).
-        // If you dont call this function default vertex normals will be calculated.
-
-        for x in 0 .. size {
-            for y in 0 .. size {
-                for z in 0 .. size {
-                    // Because i'm bluring all voxels with 5 voxels ahead, the
-                    // actual center of the voxel is 3 voxels ahead, so the
-                    // neighbors are not -1 and +1, but +1 and +3
-                    // TODO: Correct that
-                    // TODO: What I said above doesn't work (?)
-                    let dx = self.get(x+2,y,z) - self.get(x+0,y,z);
-                    let dy = self.get(x,y+2,z) - self.get(x,y+0,z);
-                    let dz = self.get(x,y,z+2) - self.get(x,y,z+0);
-
-                    let vec = -Vector3::new(dx, dy, dz).normalize();
-
-                    self.voxel_normals[(x + y*s + z*s*s) as usize] = vec;
+        self.voxel_normals = vec![Vector3::new(0.0, 0.0, 0.0); self.voxels.len()];
+
+        for z in 0 .. self.size+1 {
+            for y in 0 .. self.size+1 {
+                for x in 0 .. self.size+1 {
+                    let dx = self.get(x+1, y, z) - self.get(x-1, y, z);
+                    let dy = self.get(x, y+1, z) - self.get(x, y-1, z);
+                    let dz = self.get(x, y, z+1) - self.get(x, y, z-1);
+
+                    let g = Vector3::new(dx, dy, dz);
+                    if g.magnitude2() > 0.0 {
+                        let i = self.index(x, y, z);
+                        self.voxel_normals[i] = -g.normalize();
+                    }
                 }
             }
         }
@@ -161,24 +164,50 @@ impl<'a> Builder<'a> {
         let x = pos.x as i32;
         let y = pos.y as i32;
         let z = pos.z as i32;
-        
+
         let fx = pos.x - x as f32;
         let fy = pos.y - y as f32;
         let fz = pos.z - z as f32;
-        
-        let x0 = self.get_normal(x,y,z) * (1.0-fx) + self.get_normal(x+1,y,z) * fx;
-        let x1 = self.get_normal(x,y,z+1) * (1.0-fx) + self.get_normal(x+1,y,z+1) * fx;
-        
-        let x2 = self.get_normal(x,y+1,z) * (1.0-fx) + self.get_normal(x+1,y+1,z) * fx;
-        let x3 = self.get_normal(x,y+1,z+1) * (1.0-fx) + self.get_normal(x+1,y+1,z+1) * fx;
-        
+
+        // Vertices on the far faces have nothing after them
+        let xn = (x+1).min(self.size);
+        let yn = (y+1).min(self.size);
+        let zn = (z+1).min(self.size);
+
+        let x0 = self.get_normal(x,y,z) * (1.0-fx) + self.get_normal(xn,y,z) * fx;
+        let x1 = self.get_normal(x,y,zn) * (1.0-fx) + self.get_normal(xn,y,zn) * fx;
+
+        let x2 = self.get_normal(x,yn,z) * (1.0-fx) + self.get_normal(xn,yn,z) * fx;
+        let x3 = self.get_normal(x,yn,zn) * (1.0-fx) + self.get_normal(xn,yn,zn) * fx;
+
         let z0 = x0 * (1.0-fz) + x1 * fz;
         let z1 = x2 * (1.0-fz) + x3 * fz;
-        
-        (z0 * (1.0-fy) + z1 * fy).normalize()
+
+        let n = z0 * (1.0-fy) + z1 * fy;
+        if n.magnitude2() > 0.0 { n.normalize() } else { n }
     }
 
-    fn create_vertex (&mut self, pos: [f32; 3], cube: [f32; 8], a: usize, b: usize) -> u16 {
+    // The vertices and triangles found, with the normals of the voxels. The
+    // triangles are moved out of the builder.
+    fn take_mesh (&mut self) -> Mesh {
+        let vertices = self.vertices.iter().zip(self.vertex_materials.iter()).map(
+            |(pos, mat)| Vertex::with_material(*pos, *mat)
+        ).collect();
+        let indices = ::std::mem::take(&mut self.indices);
+        let mut mesh = Mesh { indices, vertices };
+
+        //Each verts in the mesh generated is its position in the voxel array
+        //and you can use this to find what the normal at this position.
+        //The verts are not at whole numbers though so you need to use trilinear interpolation
+        //to find the normal for that position
+        for vertex in mesh.vertices.iter_mut() {
+            vertex.normal = self.interpolate_normal(vertex.pos);
+        }
+
+        mesh
+    }
+
+    fn create_vertex (&mut self, pos: [f32; 3], cube: [f32; 8], a: usize, b: usize) -> u32 {
         let voff = VERTEX_OFFSET[a];
         let voffb = VERTEX_OFFSET[b];
 
@@ -195,9 +224,18 @@ impl<'a> Builder<'a> {
         edge_vertex.y = pos[1] + voff[1] as f32 + offset * direction[1] as f32;
         edge_vertex.z = pos[2] + voff[2] as f32 + offset * direction[2] as f32;
 
+        // The vertex takes the material of the solid end of the edge
+        let solid = if cube[a] > cube[b] {voff} else {voffb};
+        let material = self.get_material(
+            pos[0] as i32 + solid[0],
+            pos[1] as i32 + solid[1],
+            pos[2] as i32 + solid[2]
+        );
+
         let index = self.vertices.len();
         self.vertices.push(edge_vertex);
-        index as u16
+        self.vertex_materials.push(material);
+        index as u32
     }
 
     fn cube(&mut self, pos: [f32; 3], cube: [f32; 8]) {
@@ -246,13 +284,29 @@ impl<'a> Builder<'a> {
         let z = pos[2] as usize;
         let s = self.size as usize + 1;
 
-        // Bottom edges. Don't exist at the bottom plane.
+        // Bottom edges. At the bottom plane there are no cubes below that
+        // made them, so they are made here and shared like the top ones.
         if pos[1] == 0.0 {
-            // TODO: Cubes should also share bottom edges
-            edge!(0, self.create_vertex(pos, cube, 0, 1));
-            edge!(1, self.create_vertex(pos, cube, 1, 2));
-            edge!(2, self.create_vertex(pos, cube, 2, 3));
-            edge!(3, self.create_vertex(pos, cube, 3, 0));
+            edge!(0,
+                if z == 0 {
+                    self.create_vertex(pos, cube, 0, 1)
+                } else { self.xbuf[x + z*s] }
+            );
+            edge!(1, {
+                let index = self.create_vertex(pos, cube, 1, 2);
+                self.zbuf[x+1 + z*s] = index;
+                index
+            });
+            edge!(2, {
+                let index = self.create_vertex(pos, cube, 2, 3);
+                self.xbuf[x + (z+1)*s] = index;
+                index
+            });
+            edge!(3,
+                if x == 0 {
+                    self.create_vertex(pos, cube, 3, 0)
+                } else { self.zbuf[x + z*s] }
+            );
         } else {
             edge!(0, self.xbuf[x + z*s]);
             edge!(1, self.zbuf[x+1 + z*s]);
@@ -313,6 +367,11 @@ impl<'a> Builder<'a> {
             } else { self.ybuf_next[x] }
         });
     
+        if self.manifold {
+            self.manifold_cube(pos, cube, &edge_vertex_indices);
+            return;
+        }
+
         //Find the point of intersection of the surface with each edge, WAAY simpler without buffers.
         /*for i in 0 .. 12 {
 
@@ -392,36 +451,18 @@ impl Mesher for MarchingCubes {
         println!("Marching the cubes...");
         let now = ::std::time::Instant::now();
 
-        let mut builder = Builder::new(source, self.size);
+        let mut builder = Builder::new(source, self.size, self.smoothing);
+        builder.manifold = self.manifold;
         builder.fill_voxels();
-        if self.smooth {
-            builder.blur();
+        builder.blur();
 
-            // Comment this line for default normal calculation
-            builder.calculate_voxel_normals();
-        }
+        // Also without smoothing, the normals of the triangles would change
+        // at the chunk borders, where the triangles of the other chunk are
+        // missing
+        builder.calculate_voxel_normals();
 
         builder.mesh();
-
-        let vertices = builder.vertices.iter().map(|pos| Vertex::from_pos(*pos)).collect();
-        let indices = ::std::mem::replace(&mut builder.indices, vec![]);
-        let mut mesh = Mesh { indices: indices, vertices: vertices };
-
-
-        if builder.voxel_normals.len() > 0 {
-            //Each verts in the mesh generated is its position in the voxel array
-            //and you can use this to find what the normal at this position.
-            //The verts are not at whole numbers though so you need to use trilinear interpolation
-            //to find the normal for that position
-            
-            for i in 0 .. mesh.vertices.len() {
-                let mut vertex = &mut mesh.vertices[i];
-                let normal = builder.interpolate_normal(vertex.pos);
-                vertex.normal = normal;
-            }
-        } else {
-            calculate_normals(&mut mesh);
-        }
+        let mesh = builder.take_mesh();
 
         let tm = now.elapsed();
         println!("The cubes marched in {} ms",
@@ -429,4 +470,125 @@ impl Mesher for MarchingCubes {
 
         mesh
     }
-}
\ No newline at end of file
+
+    fn size (&self) -> i32 { self.size }
+
+    fn padding (&self) -> i32 { self.smoothing.padding() }
+
+    // The blurred chunks don't have the densities of the source at the
+    // faces, so only without smoothing
+    fn transition (&mut self, mesh: &mut Mesh, source: &dyn VoxelSource, faces: &[Transition]) {
+        if self.smoothing != Smoothing::None { return; }
+        transition::transition(mesh, source, self.size, faces, self.manifold);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use voxel_source::Translate;
+    use voxel_source::sdf::{Sphere, Vector};
+
+    // Two chunks next to each other in the axis have to put the same
+    // vertices on the face between them, with the same normals
+    fn check_border (smoothing: Smoothing, axis: usize) {
+        let size = 16;
+        let mut center = [8.6, 7.2, 9.1];
+        center[axis] = 15.3;
+        let sphere = || Sphere {
+            center: Vector::new(center[0], center[1], center[2]),
+            radius: 9.4,
+            material: 0
+        };
+        let mut offset = [0; 3];
+        offset[axis] = -size;
+
+        let mut mesher = MarchingCubes { size, smoothing, manifold: false };
+        let a = mesher.mesh(&Translate { source: sphere(), x: 0, y: 0, z: 0 });
+        let b = mesher.mesh(&Translate { source: sphere(), x: offset[0], y: offset[1], z: offset[2] });
+
+        let coords = |v: &::mesh::Vertex| [v.pos.x, v.pos.y, v.pos.z];
+        let mut shared = 0;
+        for va in a.vertices.iter().filter(|v| coords(v)[axis] == size as f32) {
+            let pa = coords(va);
+            let vb = b.vertices.iter().find(|v| {
+                let pb = coords(v);
+                (0 .. 3).all(|i| if i == axis { pb[i] == 0.0 } else { (pb[i] - pa[i]).abs() < 1e-4 })
+            }).expect("missing border vertex in the next chunk");
+
+            assert!((va.normal - vb.normal).magnitude() < 1e-4,
+                "normals differ at {:?}: {:?} and {:?}", va.pos, va.normal, vb.normal);
+            shared += 1;
+        }
+        assert!(shared > 0);
+    }
+
+    #[test]
+    fn border_normals_match () {
+        for axis in 0 .. 3 { check_border(Smoothing::None, axis); }
+    }
+
+    #[test]
+    fn box_border_normals_match () {
+        check_border(Smoothing::Box(2), 0);
+    }
+
+    #[test]
+    fn box_border_normals_match_in_y_and_z () {
+        check_border(Smoothing::Box(2), 1);
+        check_border(Smoothing::Box(2), 2);
+    }
+
+    #[test]
+    fn gaussian_border_normals_match () {
+        check_border(Smoothing::Gaussian(3), 0);
+    }
+
+    #[test]
+    fn gaussian_border_normals_match_in_y_and_z () {
+        check_border(Smoothing::Gaussian(3), 1);
+        check_border(Smoothing::Gaussian(3), 2);
+    }
+
+    // A single cell, the voxels outside of it are the same as the nearest
+    // corner
+    struct Cell([f32; 8]);
+
+    impl VoxelSource for Cell {
+        fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+            let p = [x.clamp(0, 1), y.clamp(0, 1), z.clamp(0, 1)];
+            self.0[VERTEX_OFFSET.iter().position(|&o| o == p).unwrap()]
+        }
+    }
+
+    // Every sign of the corners, with densities that put the saddles of
+    // the faces and of the inside on both sides
+    #[test]
+    fn manifold_cells_have_no_shared_edges () {
+        let mut mesher = MarchingCubes { size: 1, smoothing: Smoothing::None, manifold: true };
+        let mut seed: u32 = 7;
+        for signs in 0 .. 256 {
+            for _ in 0 .. 16 {
+                let mut cube = [0.0; 8];
+                for (i, v) in cube.iter_mut().enumerate() {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    let magnitude = 0.05 + (seed >> 8) as f32 / (1 << 24) as f32;
+                    *v = if signs & (1 << i) != 0 { magnitude } else { -magnitude };
+                }
+                let report = mesher.mesh(&Cell(cube)).validate();
+                assert!(report.is_valid(), "{:?}: {}", cube, report);
+            }
+        }
+    }
+
+    // Two opposite solid corners, connected through the middle, are joined
+    // with a tube, so there are vertices besides the six of the edges
+    #[test]
+    fn manifold_cells_join_the_corners_inside () {
+        let mut mesher = MarchingCubes { size: 1, smoothing: Smoothing::None, manifold: true };
+        let cube = [1.0, -0.2, -0.2, -0.2, -0.2, -0.2, 1.0, -0.2];
+        let mesh = mesher.mesh(&Cell(cube));
+        assert!(mesh.vertices.len() > 6, "{} {}", mesh.vertices.len(), mesh.indices.len());
+        let report = mesh.validate();
+        assert!(report.is_valid(), "{}", report);
+    }
+}
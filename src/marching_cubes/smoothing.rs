@@ -0,0 +1,49 @@
+
+// How the densities are blurred before marching. The blur is separable, one
+// pass of the kernel in each axis, and centered, so the surface doesn't move
+// on flat ground.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Smoothing {
+    None,
+    // Same weight for every voxel up to the radius at each side
+    Box(i32),
+    // The radius is two standard deviations, the weights after it are left
+    // out
+    Gaussian(i32),
+}
+
+impl Smoothing {
+    pub fn radius (&self) -> i32 {
+        match *self {
+            Smoothing::None => 0,
+            Smoothing::Box(r) | Smoothing::Gaussian(r) => r.max(0),
+        }
+    }
+
+    // Voxels read outside of the chunk at each side. The normals are the
+    // gradient of the blurred densities, so they follow the kernel, and
+    // they need them one voxel outside of the chunk, which reads the radius
+    // more.
+    pub fn padding (&self) -> i32 {
+        self.radius() + 1
+    }
+
+    // The weights from -radius to radius, they add up to one so the
+    // densities keep their scale
+    pub fn kernel (&self) -> Vec<f32> {
+        let r = self.radius();
+        if r == 0 { return vec![1.0]; }
+
+        let weights: Vec<f32> = match *self {
+            Smoothing::None => vec![1.0],
+            Smoothing::Box(_) => vec![1.0; (2*r + 1) as usize],
+            Smoothing::Gaussian(_) => {
+                let sigma = r as f32 / 2.0;
+                (-r .. r+1).map(|i| (-(i*i) as f32 / (2.0*sigma*sigma)).exp()).collect()
+            },
+        };
+        let sum: f32 = weights.iter().sum();
+        weights.iter().map(|w| w / sum).collect()
+    }
+}
@@ -0,0 +1,182 @@
+
+// Marching tetrahedra. Each cell is split in six tetrahedra, and those only
+// have unambiguous cases, so the surface has no holes. Everything else, the
+// voxels, the smoothing and the normals, is the same as the cubes.
+
+use mesher::Mesher;
+use cgmath::{Vector3, InnerSpace};
+use voxel_source::VoxelSource;
+use mesh::Mesh;
+
+use super::{Builder, Smoothing, get_offset};
+use super::data::VERTEX_OFFSET;
+
+pub struct MarchingTetrahedra { pub size: i32, pub smoothing: Smoothing }
+
+// The tetrahedra of a cell, with the vertices of VERTEX_OFFSET. All of them
+// go around the diagonal from 0 to 6, and the faces of the cell are split
+// the same way in the cells next to it, so the triangles match.
+const TETRAHEDRA: [[usize; 4]; 6] = [
+    [0, 1, 5, 6], [0, 1, 2, 6],
+    [0, 4, 5, 6], [0, 4, 7, 6],
+    [0, 3, 2, 6], [0, 3, 7, 6],
+];
+
+// Every edge of the tetrahedra is one of these from one of its voxels
+const DIRECTIONS: [[i32; 3]; 7] = [
+    [1, 0, 0], [0, 1, 0], [0, 0, 1],
+    [1, 1, 0], [1, 0, 1], [0, 1, 1],
+    [1, 1, 1],
+];
+
+const NONE: u32 = u32::MAX;
+
+struct Tetrahedra<'b, 'a: 'b> {
+    builder: &'b mut Builder<'a>,
+    // The vertex on each edge starting at the voxels of two layers, the one
+    // below the cells and the one above, like the caches of the cubes
+    cache: Vec<u32>,
+}
+
+impl<'b, 'a> Tetrahedra<'b, 'a> {
+    fn cache_index (&self, p: [i32; 3], direction: usize) -> usize {
+        let n = self.builder.size + 1;
+        let layer = p[1] & 1;
+        (((p[0] + p[2]*n + layer*n*n) * 7) as usize) + direction
+    }
+
+    // The vertex on the edge between the two voxels, made only once
+    fn vertex (&mut self, a: [i32; 3], b: [i32; 3]) -> u32 {
+        let d = [b[0]-a[0], b[1]-a[1], b[2]-a[2]];
+        let (a, b, d) = if d.iter().any(|&c| c < 0) {
+            (b, a, [-d[0], -d[1], -d[2]])
+        } else { (a, b, d) };
+
+        let direction = DIRECTIONS.iter().position(|&dir| dir == d).unwrap();
+        let ix = self.cache_index(a, direction);
+        if self.cache[ix] != NONE { return self.cache[ix]; }
+
+        let builder = &mut *self.builder;
+        let va = builder.get(a[0], a[1], a[2]);
+        let vb = builder.get(b[0], b[1], b[2]);
+        let t = get_offset(va, vb);
+
+        let pa = Vector3::new(a[0] as f32, a[1] as f32, a[2] as f32);
+        let pb = Vector3::new(b[0] as f32, b[1] as f32, b[2] as f32);
+
+        // The vertex takes the material of the solid end of the edge
+        let solid = if va > vb { a } else { b };
+        let material = builder.get_material(solid[0], solid[1], solid[2]);
+
+        let index = builder.vertices.len() as u32;
+        builder.vertices.push(pa + (pb - pa) * t);
+        builder.vertex_materials.push(material);
+
+        self.cache[ix] = index;
+        index
+    }
+
+    // Facing the air, like the cubes
+    fn triangle (&mut self, a: u32, b: u32, c: u32, out: Vector3<f32>) {
+        let builder = &mut *self.builder;
+        let (pa, pb, pc) = (
+            builder.vertices[a as usize],
+            builder.vertices[b as usize],
+            builder.vertices[c as usize]
+        );
+        if (pb - pa).cross(pc - pa).dot(out) >= 0.0 {
+            builder.indices.extend_from_slice(&[a, b, c]);
+        } else {
+            builder.indices.extend_from_slice(&[a, c, b]);
+        }
+    }
+
+    fn tetrahedron (&mut self, corners: [[i32; 3]; 4]) {
+        let mut solid = vec![];
+        let mut air = vec![];
+        for &p in corners.iter() {
+            if self.builder.get(p[0], p[1], p[2]) > 0.0 { solid.push(p); } else { air.push(p); }
+        }
+        if solid.is_empty() || air.is_empty() { return; }
+
+        let center = |ps: &Vec<[i32; 3]>| {
+            let mut c = Vector3::new(0.0, 0.0, 0.0);
+            for p in ps.iter() { c += Vector3::new(p[0] as f32, p[1] as f32, p[2] as f32); }
+            c / ps.len() as f32
+        };
+        let out = center(&air) - center(&solid);
+
+        match solid.len() {
+            // One corner apart from the other three
+            1 | 3 => {
+                let (lone, rest) = if solid.len() == 1 { (solid[0], &air) } else { (air[0], &solid) };
+                let a = self.vertex(lone, rest[0]);
+                let b = self.vertex(lone, rest[1]);
+                let c = self.vertex(lone, rest[2]);
+                self.triangle(a, b, c, out);
+            },
+            // A quad, around the four edges that cross
+            _ => {
+                let a = self.vertex(solid[0], air[0]);
+                let b = self.vertex(solid[0], air[1]);
+                let c = self.vertex(solid[1], air[1]);
+                let d = self.vertex(solid[1], air[0]);
+                self.triangle(a, b, c, out);
+                self.triangle(a, c, d, out);
+            },
+        }
+    }
+
+    fn march (&mut self) {
+        let size = self.builder.size;
+        let n = (size + 1) as usize;
+        self.cache = vec![NONE; n*n*2*7];
+
+        for y in 0 .. size {
+            // The layer above gets the voxels of the next one, the one below
+            // keeps the edges shared with the cells before
+            let above = ((y+1) & 1) as usize;
+            for i in above*n*n*7 .. (above+1)*n*n*7 { self.cache[i] = NONE; }
+
+            for z in 0 .. size {
+                for x in 0 .. size {
+                    for tetrahedron in TETRAHEDRA.iter() {
+                        let mut corners = [[0; 3]; 4];
+                        for i in 0 .. 4 {
+                            let off = VERTEX_OFFSET[tetrahedron[i]];
+                            corners[i] = [x + off[0], y + off[1], z + off[2]];
+                        }
+                        self.tetrahedron(corners);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Mesher for MarchingTetrahedra {
+    fn mesh (&mut self, source: &dyn VoxelSource) -> Mesh {
+
+        println!("Marching the tetrahedra...");
+        let now = ::std::time::Instant::now();
+
+        let mut builder = Builder::new(source, self.size, self.smoothing);
+        builder.fill_voxels();
+        builder.blur();
+        builder.calculate_voxel_normals();
+
+        Tetrahedra { builder: &mut builder, cache: vec![] }.march();
+        let mesh = builder.take_mesh();
+
+        let tm = now.elapsed();
+        println!("The tetrahedra marched in {} ms, with {} triangles",
+            (tm.as_secs()*1000) + tm.subsec_millis() as u64,
+            mesh.indices.len() / 3);
+
+        mesh
+    }
+
+    fn size (&self) -> i32 { self.size }
+
+    fn padding (&self) -> i32 { self.smoothing.padding() }
+}
@@ -0,0 +1,474 @@
+
+// Transition cells, after Transvoxel (http://transvoxel.org/). They close the
+// cracks between a chunk and the chunks with more detail next to it.
+//
+// The regular cells at a face with transitions are shrunk to the inside of
+// the chunk, and the room left between them and the face is filled with a
+// transition cell for each voxel square. The inner side of a transition cell
+// is the face of the shrunk regular cell, and the outer side is the face of
+// the chunk sampled with the detail of the neighbor, so it's contour is the
+// same the neighbor has there. Squares of the face without a neighbor with
+// more detail get a cell too, that only joins the two sides.
+//
+// Instead of Lengyel's tables, which are only for neighbors with twice the
+// detail, each cell is triangulated from the contours of it's faces, like
+// the manifold cells: every crossed edge of the cell belongs to one loop,
+// and each loop is closed with a fan. On the inner side the contour is the
+// border of the regular mesh, so the cells share it's vertices and the
+// result has no holes.
+//
+// Once a chunk has transitions the cells at all of it's faces are shrunk,
+// the ones without get cells with the detail of the chunk. Otherwise the
+// faces next to the ones with transitions would have their vertices moved,
+// and not match the neighbors. Where two faces meet, the cells are cut
+// diagonally at the edge of the chunk, so each one is the part of the
+// shrunk layer closer to it's own face.
+
+use std::collections::HashMap;
+
+use cgmath::{Vector3, InnerSpace};
+use voxel_source::{VoxelSource, Region, Voxel};
+use mesher::Transition;
+use mesh::{Mesh, Vertex};
+
+use super::get_offset;
+use super::data::TARGET;
+
+// The part of a voxel the regular cells at the face give to the transition
+// cells
+const WIDTH: f32 = 0.5;
+
+// A point of the lattice of the most detailed neighbor, and whether it's on
+// the shrunk side
+type Key = ([i32; 3], bool);
+
+#[derive(Clone, Copy)]
+struct Sample {
+    key: Key,
+    pos: Vector3<f32>,
+    voxel: Voxel,
+}
+
+// A face of the chunk with transitions, the detail of each of it's squares
+// and the voxels of the face, with a layer at each side for the gradients
+struct Side {
+    details: Vec<i32>,
+    region: Region,
+}
+
+struct Builder<'a> {
+    size: i32,
+    // Voxels of the most detailed neighbor in a voxel of the chunk
+    detail: i32,
+    manifold: bool,
+    // Two for each axis, the one at 0 and the one at size
+    sides: Vec<Side>,
+    mesh: &'a mut Mesh,
+    // The vertex of each crossed edge
+    crossings: HashMap<(Key, Key), u32>,
+}
+
+impl<'a> Builder<'a> {
+    // Position of the side in it's axis, in voxels of the chunk
+    fn plane (&self, side: usize) -> i32 {
+        if side % 2 == 1 { self.size } else { 0 }
+    }
+
+    // Moves the points next to the faces with transitions to the inside
+    fn shrink (&self, p: Vector3<f32>) -> Vector3<f32> {
+        let mut c = [p.x, p.y, p.z];
+        let s = self.size as f32;
+        for c in c.iter_mut() {
+            if *c > s - 1.0 {
+                *c = s - 1.0 + (*c - (s - 1.0)) * (1.0 - WIDTH);
+            }
+            if *c < 1.0 {
+                *c = 1.0 - (1.0 - *c) * (1.0 - WIDTH);
+            }
+        }
+        Vector3::new(c[0], c[1], c[2])
+    }
+
+    // The region of a side with the point on it's face
+    fn region (&self, p: [i32; 3]) -> &Region {
+        for (s, side) in self.sides.iter().enumerate() {
+            if p[s / 2] == self.plane(s) * self.detail { return &side.region; }
+        }
+        panic!("transition point out of the faces");
+    }
+
+    fn voxel (&self, p: [i32; 3], region: &Region) -> Voxel {
+        let o = region.origin;
+        region.get(p[0] - o[0], p[1] - o[1], p[2] - o[2])
+    }
+
+    fn gradient (&self, p: [i32; 3]) -> Vector3<f32> {
+        let region = self.region(p);
+        let mut g = [0.0; 3];
+        for i in 0 .. 3 {
+            let (mut a, mut b) = (p, p);
+            a[i] -= 1;
+            b[i] += 1;
+            g[i] = self.voxel(b, region).density - self.voxel(a, region).density;
+        }
+        Vector3::new(g[0], g[1], g[2])
+    }
+
+    fn sample (&self, p: [i32; 3], inner: bool) -> Sample {
+        let d = self.detail as f32;
+        let pos = Vector3::new(p[0] as f32 / d, p[1] as f32 / d, p[2] as f32 / d);
+        Sample {
+            key: (p, inner),
+            pos: if inner { self.shrink(pos) } else { pos },
+            voxel: self.voxel(p, self.region(p)),
+        }
+    }
+
+    // The vertex where the surface crosses the edge, made once for all the
+    // cells around it
+    fn crossing (&mut self, a: &Sample, b: &Sample) -> u32 {
+        let (a, b) = if a.key <= b.key { (a, b) } else { (b, a) };
+        if let Some(&index) = self.crossings.get(&(a.key, b.key)) {
+            return index;
+        }
+
+        let t = get_offset(a.voxel.density, b.voxel.density);
+        let (ga, gb) = (self.gradient(a.key.0), self.gradient(b.key.0));
+        let material = if a.voxel.density > b.voxel.density { a.voxel.material } else { b.voxel.material };
+
+        let mut vertex = Vertex::with_material(a.pos + (b.pos - a.pos) * t, material);
+        let gradient = ga + (gb - ga) * t;
+        if gradient.magnitude2() > 0.0 {
+            vertex.normal = -gradient.normalize();
+        }
+
+        let index = self.mesh.vertices.len() as u32;
+        self.mesh.vertices.push(vertex);
+        self.crossings.insert((a.key, b.key), index);
+        index
+    }
+
+    // The most detail of the squares next to the segment between two points
+    // of the lattice of the chunk, on the faces with transitions
+    fn segment_detail (&self, p0: [i32; 3], p1: [i32; 3]) -> i32 {
+        let mut detail = 1;
+        for (s, side) in self.sides.iter().enumerate() {
+            let (axis, w) = (s / 2, self.plane(s));
+            if p0[axis] != w || p1[axis] != w { continue; }
+
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let (along, across) = if p0[u] != p1[u] { (u, v) } else { (v, u) };
+            for &offset in [-1, 0].iter() {
+                let mut square = [0; 3];
+                square[along] = p0[along].min(p1[along]);
+                square[across] = p0[across] + offset;
+                let (i, j) = (square[u], square[v]);
+                if i < 0 || j < 0 || i >= self.size || j >= self.size { continue; }
+                detail = detail.max(side.details[(i + j*self.size) as usize]);
+            }
+        }
+        detail
+    }
+
+    // The points of the lattice of the neighbor from a to b, without b. On
+    // the border of a square they have the detail of both sides.
+    fn path (&self, a: [i32; 3], b: [i32; 3], detail: i32) -> Vec<[i32; 3]> {
+        let along = (0 .. 3).find(|&i| a[i] != b[i]).unwrap();
+        let step = if b[along] > a[along] { self.detail / detail } else { -self.detail / detail };
+        let mut points = vec![];
+        let mut p = a;
+        while p != b {
+            points.push(p);
+            p[along] += step;
+        }
+        points
+    }
+
+    // The segments of the contour of a face, from an edge where it goes from
+    // solid to air to one where it goes back, so each has the solid at it's
+    // left. The samples go counter clockwise seen from outside of the cell.
+    fn contour (&mut self, samples: &[Sample], segments: &mut Vec<(u32, u32)>) {
+        let n = samples.len();
+        let solid: Vec<bool> = samples.iter().map(|s| s.voxel.density > TARGET).collect();
+        let outs: Vec<usize> = (0 .. n).filter(|&i| solid[i] && !solid[(i+1) % n]).collect();
+
+        // Two opposite solid corners. Like the regular cells, the solid is
+        // connected, or with the asymptotic decider for manifold cells.
+        let separated = self.manifold && n == 4 && outs.len() == 2 && {
+            let v: Vec<f32> = samples.iter().map(|s| s.voxel.density).collect();
+            (v[0]*v[2] - v[1]*v[3]) / ((v[0] + v[2]) - (v[1] + v[3])) <= TARGET
+        };
+
+        for &out in outs.iter() {
+            let mut target = out;
+            loop {
+                target = if separated { (target + n - 1) % n } else { (target + 1) % n };
+                if !solid[target] && solid[(target+1) % n] { break; }
+            }
+            let from = self.crossing(&samples[out], &samples[(out+1) % n]);
+            let to = self.crossing(&samples[target], &samples[(target+1) % n]);
+            segments.push((from, to));
+        }
+    }
+
+    // The loops go around the solid, the triangles are the other way
+    fn fan (&mut self, contour: &[u32]) {
+        let n = contour.len();
+        let p = |mesh: &Mesh, i: usize| mesh.vertices[contour[i] as usize].pos;
+
+        if n == 3 {
+            self.mesh.indices.extend_from_slice(&[contour[0], contour[2], contour[1]]);
+            return;
+        }
+        if n == 4 {
+            // Split by the shorter diagonal
+            let (d0, d1) = ((p(self.mesh, 2) - p(self.mesh, 0)).magnitude2(), (p(self.mesh, 3) - p(self.mesh, 1)).magnitude2());
+            let r = if d0 <= d1 { 0 } else { 1 };
+            let q = |i: usize| contour[(r + i) % 4];
+            self.mesh.indices.extend_from_slice(&[q(0), q(2), q(1), q(0), q(3), q(2)]);
+            return;
+        }
+
+        let mut center = Vector3::new(0.0, 0.0, 0.0);
+        let mut normal = Vector3::new(0.0, 0.0, 0.0);
+        for &i in contour.iter() {
+            center += self.mesh.vertices[i as usize].pos;
+            normal += self.mesh.vertices[i as usize].normal;
+        }
+        let material = self.mesh.vertices[contour[0] as usize].material;
+        let mut vertex = Vertex::with_material(center / n as f32, material);
+        if normal.magnitude2() > 0.0 { vertex.normal = normal.normalize(); }
+
+        let c = self.mesh.vertices.len() as u32;
+        self.mesh.vertices.push(vertex);
+        for i in 0 .. n {
+            self.mesh.indices.extend_from_slice(&[c, contour[(i+1) % n], contour[i]]);
+        }
+    }
+
+    // The transition cell of the square (i, j) of a side. The inner segments
+    // are the border of the regular mesh on the square.
+    fn cell (&mut self, side: usize, i: i32, j: i32, inner: &[(u32, u32)]) {
+        let (axis, w, d) = (side / 2, self.plane(side), self.detail);
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let k = self.sides[side].details[(i + j*self.size) as usize];
+
+        // Lattice of the chunk, and of the neighbor
+        let corner = |a: i32, b: i32| {
+            let mut p = [0; 3];
+            p[axis] = w;
+            p[u] = i + a;
+            p[v] = j + b;
+            p
+        };
+        let fine = |p: [i32; 3]| [p[0]*d, p[1]*d, p[2]*d];
+        let corners = [corner(0, 0), corner(1, 0), corner(1, 1), corner(0, 1)];
+
+        let mut faces: Vec<Vec<Sample>> = vec![];
+
+        // The outer side, in squares of the detail of the neighbor. Their
+        // edges on the border of the cell have the detail of the other side.
+        let step = d / k;
+        for b in 0 .. k {
+            for a in 0 .. k {
+                let mut p = fine(corners[0]);
+                p[u] += a * step;
+                p[v] += b * step;
+                let mut q = [p; 4];
+                q[1][u] += step;
+                q[2][u] += step;
+                q[2][v] += step;
+                q[3][v] += step;
+
+                let mut samples = vec![];
+                for c in 0 .. 4 {
+                    let (from, to) = (q[c], q[(c+1) % 4]);
+                    let edge = (0 .. 3).find(|&x| from[x] != to[x]).unwrap();
+                    let across = if edge == u { v } else { u };
+                    let on_border = from[across] % d == 0;
+                    let detail = if on_border {
+                        // The segment of the lattice of the chunk it's in
+                        let mut s0 = [from[0] / d, from[1] / d, from[2] / d];
+                        s0[edge] = from[edge].min(to[edge]) / d;
+                        let mut s1 = s0;
+                        s1[edge] += 1;
+                        self.segment_detail(s0, s1).max(k)
+                    } else { k };
+                    for point in self.path(from, to, detail) {
+                        samples.push(self.sample(point, false));
+                    }
+                }
+                faces.push(samples);
+            }
+        }
+
+        // The sides, from the edge of the inner side to the one of the outer
+        for c in 0 .. 4 {
+            let (c0, c1) = (corners[c], corners[(c+1) % 4]);
+            let detail = self.segment_detail(c0, c1).max(k);
+            let mut samples = vec![self.sample(fine(c0), true), self.sample(fine(c1), true)];
+            for point in self.path(fine(c1), fine(c0), detail) {
+                samples.push(self.sample(point, false));
+            }
+            samples.push(self.sample(fine(c0), false));
+            faces.push(samples);
+        }
+
+        // Counter clockwise seen from outside of the cell
+        let mut center = Vector3::new(0.0, 0.0, 0.0);
+        for &p in corners.iter() {
+            center += self.sample(fine(p), true).pos + self.sample(fine(p), false).pos;
+        }
+        center /= 8.0;
+        for samples in faces.iter_mut() {
+            let n = samples.len();
+            let mut area = Vector3::new(0.0, 0.0, 0.0);
+            let mut middle = Vector3::new(0.0, 0.0, 0.0);
+            for s in 0 .. n {
+                area += samples[s].pos.cross(samples[(s+1) % n].pos);
+                middle += samples[s].pos;
+            }
+            if area.dot(middle / n as f32 - center) < 0.0 {
+                samples.reverse();
+            }
+        }
+
+        let mut segments = inner.to_vec();
+        for samples in faces.iter() {
+            self.contour(samples, &mut segments);
+        }
+
+        // Follow the segments into loops
+        let next: HashMap<u32, u32> = segments.iter().cloned().collect();
+        let mut starts: Vec<u32> = next.keys().cloned().collect();
+        starts.sort();
+        let mut used: Vec<u32> = vec![];
+        for &start in starts.iter() {
+            if used.contains(&start) { continue; }
+            let mut contour = vec![];
+            let mut e = start;
+            loop {
+                contour.push(e);
+                used.push(e);
+                e = match next.get(&e) {
+                    Some(&e) => e,
+                    // Only if the border of the regular mesh doesn't agree
+                    // with the voxels, leave a hole instead of a wrong cell
+                    None => { contour.clear(); break; }
+                };
+                if e == start || used.contains(&e) { break; }
+            }
+            if contour.len() >= 3 && e == start {
+                self.fan(&contour);
+            }
+        }
+    }
+}
+
+// Shrinks the regular cells of the faces of the mesh with transitions, and
+// adds the transition cells. The mesh is in voxels of the chunk, with size
+// voxels per side, and the source has the most detail of the faces.
+pub fn transition (mesh: &mut Mesh, source: &dyn VoxelSource, size: i32, faces: &[Transition], manifold: bool) {
+    let detail = match faces.iter().map(|f| f.detail).max() {
+        Some(detail) => detail,
+        None => return,
+    };
+    let n = size * detail;
+
+    // All the faces are shrunk, otherwise the ones next to a face with
+    // transitions would move their vertices and not match the neighbors
+    let mut sides: Vec<Side> = (0 .. 6).map(|s| {
+        let axis = s / 2;
+        let mut origin = [-1; 3];
+        let mut region_size = [n + 3; 3];
+        origin[axis] = if s % 2 == 1 { n - 1 } else { -1 };
+        region_size[axis] = 3;
+        let mut region = Region::new(origin, region_size, 1);
+        source.fill_region(&mut region);
+        Side { details: vec![1; (size*size) as usize], region }
+    }).collect();
+    for face in faces.iter() {
+        let side = &mut sides[face.axis*2 + face.positive as usize];
+        for (i, &cell) in face.cells.iter().enumerate() {
+            if cell { side.details[i] = side.details[i].max(face.detail); }
+        }
+    }
+
+    let mut builder = Builder {
+        size,
+        detail,
+        manifold,
+        sides,
+        mesh,
+        crossings: HashMap::new(),
+    };
+
+    // The border of the regular mesh on each square of the faces
+    let mut uses: HashMap<(u32, u32), u32> = HashMap::new();
+    for t in builder.mesh.indices.chunks(3) {
+        for e in 0 .. 3 {
+            let (a, b) = (t[e], t[(e+1) % 3]);
+            *uses.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+    let mut borders: HashMap<(usize, i32, i32), Vec<(u32, u32)>> = HashMap::new();
+    for t in builder.mesh.indices.chunks(3) {
+        for e in 0 .. 3 {
+            let (a, b) = (t[e], t[(e+1) % 3]);
+            if uses[&(a.min(b), a.max(b))] != 1 { continue; }
+
+            let pa = builder.mesh.vertices[a as usize].pos;
+            let pb = builder.mesh.vertices[b as usize].pos;
+            let (ca, cb) = ([pa.x, pa.y, pa.z], [pb.x, pb.y, pb.z]);
+            for s in 0 .. 6 {
+                let (axis, w) = (s / 2, builder.plane(s) as f32);
+                if ca[axis] != w || cb[axis] != w { continue; }
+
+                let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                let square = |c: usize| (((ca[c] + cb[c]) / 2.0).floor() as i32).max(0).min(size - 1);
+                borders.entry((s, square(u), square(v))).or_default().push((a, b));
+                break;
+            }
+        }
+    }
+
+    // The vertices of the regular mesh on the edges of the faces are the
+    // crossings of the inner sides
+    for index in 0 .. builder.mesh.vertices.len() {
+        let p = builder.mesh.vertices[index].pos;
+        let c = [p.x, p.y, p.z];
+        for s in 0 .. 6 {
+            let axis = s / 2;
+            if c[axis] != builder.plane(s) as f32 { continue; }
+
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let (along, across) = if c[u] == c[u].floor() { (v, u) } else if c[v] == c[v].floor() { (u, v) } else { continue };
+            let mut a = [0; 3];
+            a[axis] = builder.plane(s);
+            a[across] = c[across] as i32;
+            a[along] = (c[along].floor() as i32).min(size - 1);
+            let mut b = a;
+            b[along] += 1;
+
+            let sa = builder.sample([a[0]*detail, a[1]*detail, a[2]*detail], true);
+            let sb = builder.sample([b[0]*detail, b[1]*detail, b[2]*detail], true);
+            if (sa.voxel.density > TARGET) == (sb.voxel.density > TARGET) { continue; }
+            let key = if sa.key <= sb.key { (sa.key, sb.key) } else { (sb.key, sa.key) };
+            builder.crossings.insert(key, index as u32);
+        }
+    }
+
+    for index in 0 .. builder.mesh.vertices.len() {
+        let p = builder.mesh.vertices[index].pos;
+        builder.mesh.vertices[index].pos = builder.shrink(p);
+    }
+
+    for s in 0 .. 6 {
+        for j in 0 .. size {
+            for i in 0 .. size {
+                let inner = borders.remove(&(s, i, j)).unwrap_or_default();
+                builder.cell(s, i, j, &inner);
+            }
+        }
+    }
+}
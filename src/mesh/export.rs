@@ -0,0 +1,399 @@
+
+// Writes meshes to files that other programs can open, to look at them in
+// Blender or compare the output of two meshers. Each mesh is a separate
+// object of the file, with it's name.
+
+use std::io;
+use std::io::Write;
+use std::fs::File;
+use std::path::Path;
+
+use voxel_source::{GRASS, SOILSAND};
+use super::Mesh;
+
+pub enum Format {
+  // Wavefront, text
+  Obj,
+  // Binary PLY
+  Ply,
+  // Binary glTF 2.0, a .glb file
+  Gltf,
+}
+
+impl Format {
+  pub fn from_path (path: &Path) -> Option<Format> {
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    match extension.as_ref().map(|e| &e[..]) {
+      Some("obj") => Some(Format::Obj),
+      Some("ply") => Some(Format::Ply),
+      Some("glb") => Some(Format::Gltf),
+      _ => None,
+    }
+  }
+}
+
+fn material_name (material: u8) -> String {
+  match material {
+    GRASS => "grass".to_string(),
+    SOILSAND => "soilsand".to_string(),
+    m => format!("material_{}", m),
+  }
+}
+
+// Positions and normals per vertex, and each triangle takes the material of
+// it's first vertex
+fn write_obj (objects: &[(&str, &Mesh)], out: &mut dyn Write) -> io::Result<()> {
+  writeln!(out, "# Miterra")?;
+
+  // Indices start at 1, and count the vertices of the objects before
+  let mut offset = 1;
+  for &(name, mesh) in objects.iter() {
+    writeln!(out, "o {}", name)?;
+    for v in mesh.vertices.iter() {
+      writeln!(out, "v {} {} {}", v.pos.x, v.pos.y, v.pos.z)?;
+    }
+    for v in mesh.vertices.iter() {
+      writeln!(out, "vn {} {} {}", v.normal.x, v.normal.y, v.normal.z)?;
+    }
+
+    let mut material = None;
+    for t in mesh.indices.chunks(3) {
+      let m = mesh.vertices[t[0] as usize].material;
+      if material != Some(m) {
+        writeln!(out, "usemtl {}", material_name(m))?;
+        material = Some(m);
+      }
+      let (a, b, c) = (t[0] + offset, t[1] + offset, t[2] + offset);
+      writeln!(out, "f {}//{} {}//{} {}//{}", a, a, b, b, c, c)?;
+    }
+
+    offset += mesh.vertices.len() as u32;
+  }
+  Ok(())
+}
+
+// The material is a property of the vertices, and the object a property of
+// the faces, with the names in the comments
+fn write_ply (objects: &[(&str, &Mesh)], out: &mut dyn Write) -> io::Result<()> {
+  let vertices: usize = objects.iter().map(|o| o.1.vertices.len()).sum();
+  let faces: usize = objects.iter().map(|o| o.1.indices.len() / 3).sum();
+
+  writeln!(out, "ply")?;
+  writeln!(out, "format binary_little_endian 1.0")?;
+  for (i, &(name, _)) in objects.iter().enumerate() {
+    writeln!(out, "comment object {} {}", i, name)?;
+  }
+  writeln!(out, "element vertex {}", vertices)?;
+  for p in ["x", "y", "z", "nx", "ny", "nz"].iter() {
+    writeln!(out, "property float {}", p)?;
+  }
+  writeln!(out, "property uchar material")?;
+  writeln!(out, "element face {}", faces)?;
+  writeln!(out, "property list uchar uint vertex_indices")?;
+  writeln!(out, "property int object")?;
+  writeln!(out, "end_header")?;
+
+  for &(_, mesh) in objects.iter() {
+    for v in mesh.vertices.iter() {
+      for f in [v.pos.x, v.pos.y, v.pos.z, v.normal.x, v.normal.y, v.normal.z].iter() {
+        out.write_all(&f.to_bits().to_le_bytes())?;
+      }
+      out.write_all(&[v.material])?;
+    }
+  }
+
+  let mut offset = 0;
+  for (i, &(_, mesh)) in objects.iter().enumerate() {
+    for t in mesh.indices.chunks(3) {
+      out.write_all(&[3])?;
+      for &index in t.iter() {
+        out.write_all(&(index + offset).to_le_bytes())?;
+      }
+      out.write_all(&(i as i32).to_le_bytes())?;
+    }
+    offset += mesh.vertices.len() as u32;
+  }
+  Ok(())
+}
+
+// A node for each object with a mesh of one primitive. All the data is in
+// one buffer, in the binary chunk of the file. The material goes in the
+// _MATERIAL attribute, as a float.
+fn write_gltf (objects: &[(&str, &Mesh)], out: &mut dyn Write) -> io::Result<()> {
+  let mut bin: Vec<u8> = vec![];
+  let mut views = vec![];
+  let mut accessors = vec![];
+  let mut meshes = vec![];
+  let mut nodes = vec![];
+
+  // Every view is a multiple of 4 bytes, so they are all aligned
+  let mut view = |bin: &mut Vec<u8>, data: Vec<u8>, target: u32| {
+    views.push(format!(
+      r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+      bin.len(), data.len(), target
+    ));
+    bin.extend(data);
+    views.len() - 1
+  };
+
+  for &(name, mesh) in objects.iter() {
+    // A primitive can't be empty
+    if mesh.indices.is_empty() { continue; }
+
+    let n = mesh.vertices.len();
+    let floats = |values: &mut dyn Iterator<Item=f32>| {
+      values.flat_map(|f| f.to_bits().to_le_bytes().to_vec()).collect::<Vec<u8>>()
+    };
+
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for v in mesh.vertices.iter() {
+      let p = [v.pos.x, v.pos.y, v.pos.z];
+      for i in 0 .. 3 {
+        min[i] = min[i].min(p[i]);
+        max[i] = max[i].max(p[i]);
+      }
+    }
+
+    // Array buffer and element array buffer
+    let positions = view(&mut bin, floats(&mut mesh.vertices.iter().flat_map(|v| vec![v.pos.x, v.pos.y, v.pos.z])), 34962);
+    let normals = view(&mut bin, floats(&mut mesh.vertices.iter().flat_map(|v| vec![v.normal.x, v.normal.y, v.normal.z])), 34962);
+    let materials = view(&mut bin, floats(&mut mesh.vertices.iter().map(|v| v.material as f32)), 34962);
+    let indices = view(&mut bin, mesh.indices.iter().flat_map(|i| i.to_le_bytes().to_vec()).collect(), 34963);
+
+    // Floats are 5126 and unsigned ints 5125
+    let first = accessors.len();
+    accessors.push(format!(
+      r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}}"#,
+      positions, n, min[0], min[1], min[2], max[0], max[1], max[2]
+    ));
+    accessors.push(format!(r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC3"}}"#, normals, n));
+    accessors.push(format!(r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"SCALAR"}}"#, materials, n));
+    accessors.push(format!(
+      r#"{{"bufferView":{},"componentType":5125,"count":{},"type":"SCALAR"}}"#,
+      indices, mesh.indices.len()
+    ));
+
+    let name = json_string(name);
+    meshes.push(format!(
+      r#"{{"name":{},"primitives":[{{"attributes":{{"POSITION":{},"NORMAL":{},"_MATERIAL":{}}},"indices":{},"mode":4}}]}}"#,
+      name, first, first + 1, first + 2, first + 3
+    ));
+    nodes.push(format!(r#"{{"name":{},"mesh":{}}}"#, name, meshes.len() - 1));
+  }
+
+  // glTF doesn't allow empty arrays or buffers, so without meshes there's
+  // only the scene
+  let mut json = String::from(r#"{"asset":{"version":"2.0","generator":"Miterra"},"scene":0"#);
+  let scene: Vec<String> = (0 .. nodes.len()).map(|i| i.to_string()).collect();
+  let scene = if scene.is_empty() { "{}".to_string() } else { format!(r#"{{"nodes":[{}]}}"#, scene.join(",")) };
+  let buffers = if bin.is_empty() { vec![] } else { vec![format!(r#"{{"byteLength":{}}}"#, bin.len())] };
+  let arrays = [
+    ("scenes", vec![scene]), ("nodes", nodes), ("meshes", meshes),
+    ("accessors", accessors), ("bufferViews", views), ("buffers", buffers),
+  ];
+  for &(key, ref items) in arrays.iter() {
+    if !items.is_empty() {
+      json.push_str(&format!(r#","{}":[{}]"#, key, items.join(",")));
+    }
+  }
+  json.push('}');
+  let mut json = json.into_bytes();
+
+  // The chunks are padded to 4 bytes, the json with spaces
+  let padding = |len: usize| (4 - len % 4) % 4;
+  let (jp, bp) = (padding(json.len()), padding(bin.len()));
+  json.extend(vec![b' '; jp]);
+  bin.extend(vec![0; bp]);
+
+  let bin_chunk = if bin.is_empty() { 0 } else { 8 + bin.len() };
+  let length = 12 + 8 + json.len() + bin_chunk;
+  out.write_all(b"glTF")?;
+  out.write_all(&2u32.to_le_bytes())?;
+  out.write_all(&(length as u32).to_le_bytes())?;
+
+  out.write_all(&(json.len() as u32).to_le_bytes())?;
+  out.write_all(b"JSON")?;
+  out.write_all(&json)?;
+
+  if !bin.is_empty() {
+    out.write_all(&(bin.len() as u32).to_le_bytes())?;
+    out.write_all(b"BIN\0")?;
+    out.write_all(&bin)?;
+  }
+  Ok(())
+}
+
+// Quoted and escaped for json
+fn json_string (s: &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+pub fn write (format: Format, objects: &[(&str, &Mesh)], out: &mut dyn Write) -> io::Result<()> {
+  match format {
+    Format::Obj => write_obj(objects, out),
+    Format::Ply => write_ply(objects, out),
+    Format::Gltf => write_gltf(objects, out),
+  }
+}
+
+// The format comes from the extension of the path
+pub fn save (path: &Path, objects: &[(&str, &Mesh)]) -> io::Result<()> {
+  let format = match Format::from_path(path) {
+    Some(format) => format,
+    None => return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      "unknown mesh format, use .obj, .ply or .glb"
+    )),
+  };
+  let mut out = io::BufWriter::new(File::create(path)?);
+  write(format, objects, &mut out)?;
+  out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mesh::{Vertex, Vector3};
+
+  // A triangle, a square of two triangles, and an empty one
+  fn objects () -> Vec<(&'static str, Mesh)> {
+    let vertex = |x: f32, y: f32, material: u8| Vertex::with_material(Vector3::new(x, y, 0.0), material);
+    let mut triangle = Mesh::new();
+    triangle.vertices = vec![vertex(0.0, 0.0, GRASS), vertex(1.0, 0.0, GRASS), vertex(0.0, 1.0, GRASS)];
+    triangle.indices = vec![0, 1, 2];
+
+    let mut square = Mesh::new();
+    square.vertices = vec![
+      vertex(2.0, 0.0, SOILSAND), vertex(3.0, 0.0, SOILSAND),
+      vertex(3.0, 1.0, SOILSAND), vertex(2.0, 1.0, SOILSAND),
+    ];
+    square.indices = vec![0, 1, 2, 0, 2, 3];
+
+    vec![("triangle", triangle), ("square", square), ("empty", Mesh::new())]
+  }
+
+  fn export_objects (format: Format, objects: &[(&str, Mesh)]) -> Vec<u8> {
+    let list: Vec<(&str, &Mesh)> = objects.iter().map(|&(name, ref mesh)| (name, mesh)).collect();
+    let mut out = vec![];
+    write(format, &list, &mut out).unwrap();
+    out
+  }
+
+  fn export (format: Format) -> Vec<u8> {
+    export_objects(format, &objects())
+  }
+
+  fn u32_at (bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([bytes[i], bytes[i+1], bytes[i+2], bytes[i+3]])
+  }
+
+  #[test]
+  fn obj_indices_count_the_objects_before () {
+    let text = String::from_utf8(export(Format::Obj)).unwrap();
+
+    let mut faces: Vec<Vec<Vec<u32>>> = vec![];
+    for line in text.lines() {
+      if line.starts_with("o ") { faces.push(vec![]); }
+      if let Some(line) = line.strip_prefix("f ") {
+        let face = line.split(' ').map(|v| {
+          let (position, normal) = v.split_at(v.find("//").unwrap());
+          assert_eq!(position, &normal[2..]);
+          position.parse().unwrap()
+        }).collect();
+        faces.last_mut().unwrap().push(face);
+      }
+    }
+
+    // The first vertex of the square is the fourth of the file
+    assert_eq!(faces, vec![
+      vec![vec![1, 2, 3]],
+      vec![vec![4, 5, 6], vec![4, 6, 7]],
+      vec![],
+    ]);
+    assert_eq!(text.lines().filter(|l| l.starts_with("v ")).count(), 7);
+  }
+
+  #[test]
+  fn ply_header_matches_the_payload () {
+    let bytes = export(Format::Ply);
+    let end = b"end_header\n";
+    let start = (0 .. bytes.len()).find(|&i| bytes[i..].starts_with(end)).unwrap() + end.len();
+    let header = String::from_utf8(bytes[.. start].to_vec()).unwrap();
+
+    let count = |element: &str| -> usize {
+      let line = header.lines().find(|l| l.starts_with(element)).unwrap();
+      line[element.len() ..].trim().parse().unwrap()
+    };
+    let (vertices, faces) = (count("element vertex"), count("element face"));
+    assert_eq!((vertices, faces), (7, 3));
+
+    // Six floats and the material, then the count, three indices and the
+    // object of each face
+    let face = |i: usize| start + vertices * 25 + i * 17;
+    assert_eq!(bytes.len(), face(faces));
+
+    // The square's second triangle, in the second object
+    assert_eq!(bytes[face(2)], 3);
+    let indices: Vec<u32> = (0 .. 3).map(|k| u32_at(&bytes, face(2) + 1 + k*4)).collect();
+    assert_eq!(indices, vec![3, 5, 6]);
+    assert_eq!(u32_at(&bytes, face(2) + 13), 1);
+  }
+
+  #[test]
+  fn glb_chunks_are_padded () {
+    let bytes = export(Format::Gltf);
+    assert_eq!(&bytes[0 .. 4], b"glTF");
+    assert_eq!(u32_at(&bytes, 4), 2);
+    assert_eq!(u32_at(&bytes, 8) as usize, bytes.len());
+
+    let json_length = u32_at(&bytes, 12) as usize;
+    assert_eq!(&bytes[16 .. 20], b"JSON");
+    assert_eq!(json_length % 4, 0);
+    let json = String::from_utf8(bytes[20 .. 20 + json_length].to_vec()).unwrap();
+
+    let bin = 20 + json_length;
+    let bin_length = u32_at(&bytes, bin) as usize;
+    assert_eq!(&bytes[bin + 4 .. bin + 8], b"BIN\0");
+    assert_eq!(bin_length % 4, 0);
+    assert_eq!(bin + 8 + bin_length, bytes.len());
+
+    // The buffer fits in the chunk, with less than 4 bytes of padding
+    let key = r#""buffers":[{"byteLength":"#;
+    let rest = &json[json.find(key).unwrap() + key.len() ..];
+    let buffer: usize = rest[.. rest.find('}').unwrap()].parse().unwrap();
+    assert!(buffer <= bin_length && bin_length - buffer < 4);
+
+    // The empty mesh has no node
+    assert_eq!(json.matches(r#""mesh":"#).count(), 2);
+  }
+
+  #[test]
+  fn glb_names_are_escaped () {
+    let mut objects = objects();
+    objects[0].0 = r#"a "quoted" \ name"#;
+    let bytes = export_objects(Format::Gltf, &objects);
+    let json = String::from_utf8(bytes[20 .. 20 + u32_at(&bytes, 12) as usize].to_vec()).unwrap();
+    assert_eq!(json.matches(r#""name":"a \"quoted\" \\ name""#).count(), 2);
+  }
+
+  #[test]
+  fn glb_without_triangles_has_no_buffer () {
+    let bytes = export_objects(Format::Gltf, &[("empty", Mesh::new())]);
+    let json_length = u32_at(&bytes, 12) as usize;
+    assert_eq!(bytes.len(), 20 + json_length);
+
+    let json = String::from_utf8(bytes[20 ..].to_vec()).unwrap();
+    assert_eq!(json.trim_end(), r#"{"asset":{"version":"2.0","generator":"Miterra"},"scene":0,"scenes":[{}]}"#);
+  }
+}
@@ -1,4 +1,13 @@
 
+mod simplify;
+mod validate;
+mod optimize;
+pub mod export;
+
+pub use self::simplify::Simplify;
+pub use self::validate::Report;
+pub use self::optimize::CacheReport;
+
 pub type Vector3 = ::cgmath::Vector3<f32>;
 
 // This trait has the normalize method
@@ -7,29 +16,31 @@ pub use cgmath::InnerSpace;
 pub struct Vertex {
   pub pos: Vector3,
   pub normal: Vector3,
+  pub material: u8,
+  // Ambient occlusion, from 0 in a corner to 3 with nothing around
+  pub ao: u8,
 }
 
 impl Vertex {
-  pub fn new () -> Self {
-    Vertex {
-      pos: Vector3::new(0.0, 0.0, 0.0),
-      normal: Vector3::new(0.0, 0.0, 0.0)
-    }
-  }
-
-  pub fn from_pos (pos: Vector3) -> Self {
-    Vertex { pos: pos, normal: Vector3::new(0.0, 0.0, 0.0) }
+  pub fn with_material (pos: Vector3, material: u8) -> Self {
+    Vertex { pos, normal: Vector3::new(0.0, 0.0, 0.0), material, ao: 3 }
   }
 }
 
 pub struct Mesh {
   pub vertices: Vec<Vertex>,
-  pub indices: Vec<u16>,
+  pub indices: Vec<u32>,
 }
 
 impl Mesh {
   pub fn new() -> Self { Mesh { vertices: vec![], indices: vec![] } }
 
+  // The indices in 16 bits, if all the vertices fit
+  pub fn short_indices(&self) -> Option<Vec<u16>> {
+    if self.vertices.len() > 1 << 16 { return None; }
+    Some(self.indices.iter().map(|&i| i as u16).collect())
+  }
+
   pub fn translate(&mut self, p: Vector3) {
     for vertex in self.vertices.iter_mut() {
       let old = vertex.pos;
@@ -0,0 +1,372 @@
+
+// Mesh simplification by edge collapse, from "Surface Simplification Using
+// Quadric Error Metrics" by Garland and Heckbert. Each vertex keeps the sum
+// of the planes of the triangles around it in the original mesh, as a
+// quadric, and the cheapest edge is collapsed until the mesh is small
+// enough or the next collapse would move the surface too much.
+//
+// Vertices on the open border of the mesh, and the ones the caller locks,
+// never move, so the borders of chunks next to each other stay the same.
+
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+
+use super::{Mesh, Vertex, Vector3, InnerSpace};
+
+pub struct Simplify {
+  // Stops when there are this many triangles or less
+  pub triangles: usize,
+  // Or before a collapse with more error than this, the sum of the squared
+  // distances, in the units of the mesh, from the new vertex to the planes
+  // of the original triangles it replaces
+  pub error: f32,
+}
+
+// Symmetric 4x4 matrix, the upper half by rows
+type Quadric = [f64; 10];
+
+fn plane (n: Vector3, p: Vector3) -> Quadric {
+  let (a, b, c) = (n.x as f64, n.y as f64, n.z as f64);
+  let d = -(a*p.x as f64 + b*p.y as f64 + c*p.z as f64);
+  [a*a, a*b, a*c, a*d, b*b, b*c, b*d, c*c, c*d, d*d]
+}
+
+fn add (a: &Quadric, b: &Quadric) -> Quadric {
+  let mut q = [0.0; 10];
+  for i in 0 .. 10 { q[i] = a[i] + b[i]; }
+  q
+}
+
+fn error (q: &Quadric, p: Vector3) -> f32 {
+  let (x, y, z) = (p.x as f64, p.y as f64, p.z as f64);
+  let e =
+    q[0]*x*x + 2.0*q[1]*x*y + 2.0*q[2]*x*z + 2.0*q[3]*x +
+    q[4]*y*y + 2.0*q[5]*y*z + 2.0*q[6]*y +
+    q[7]*z*z + 2.0*q[8]*z +
+    q[9];
+  e.max(0.0) as f32
+}
+
+// The point with the least error, if there's only one
+fn optimal (q: &Quadric) -> Option<Vector3> {
+  let m = [[q[0], q[1], q[2]], [q[1], q[4], q[5]], [q[2], q[5], q[7]]];
+  let b = [-q[3], -q[6], -q[8]];
+
+  let det = |m: &[[f64; 3]; 3]| {
+    m[0][0]*(m[1][1]*m[2][2] - m[1][2]*m[2][1]) -
+    m[0][1]*(m[1][0]*m[2][2] - m[1][2]*m[2][0]) +
+    m[0][2]*(m[1][0]*m[2][1] - m[1][1]*m[2][0])
+  };
+  let d = det(&m);
+  if d.abs() < 1e-6 { return None; }
+
+  // Cramer's rule
+  let mut p = [0.0; 3];
+  for i in 0 .. 3 {
+    let mut mi = m;
+    for j in 0 .. 3 { mi[j][i] = b[j]; }
+    p[i] = (det(&mi) / d) as f32;
+  }
+  Some(Vector3::new(p[0], p[1], p[2]))
+}
+
+// An edge to collapse, b into a. The stamps say which version of the
+// vertices it was made for, it's discarded if any of them changed since.
+struct Collapse {
+  cost: f32,
+  a: usize,
+  b: usize,
+  pos: Vector3,
+  stamps: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+  fn eq (&self, other: &Self) -> bool { self.cost == other.cost }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+  fn partial_cmp (&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+// The heap gives the biggest first, so the cheapest is the biggest
+impl Ord for Collapse {
+  fn cmp (&self, other: &Self) -> Ordering {
+    other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+  }
+}
+
+struct Simplifier<'a> {
+  vertices: &'a mut Vec<Vertex>,
+  triangles: Vec<[usize; 3]>,
+  alive: Vec<bool>,
+  // The triangles around each vertex, some of them may be dead
+  faces: Vec<Vec<usize>>,
+  quadrics: Vec<Quadric>,
+  locked: Vec<bool>,
+  stamps: Vec<u32>,
+  heap: BinaryHeap<Collapse>,
+}
+
+impl<'a> Simplifier<'a> {
+  fn normal (&self, t: [usize; 3]) -> Vector3 {
+    let p = |i: usize| self.vertices[t[i]].pos;
+    (p(1) - p(0)).cross(p(2) - p(0))
+  }
+
+  fn neighbors (&self, v: usize) -> Vec<usize> {
+    let mut n = vec![];
+    for &t in self.faces[v].iter() {
+      if !self.alive[t] { continue; }
+      for &u in self.triangles[t].iter() {
+        if u != v && !n.contains(&u) { n.push(u); }
+      }
+    }
+    n
+  }
+
+  fn push (&mut self, a: usize, b: usize) {
+    let (a, b) = if self.locked[b] { (b, a) } else { (a, b) };
+    if self.locked[b] { return; }
+
+    let q = add(&self.quadrics[a], &self.quadrics[b]);
+    let (pa, pb) = (self.vertices[a].pos, self.vertices[b].pos);
+
+    // The best point is only taken between the two, so the vertices never
+    // go out of the mesh, like past the faces of the chunk
+    let between = |p: Vector3| {
+      let within = |v: f32, a: f32, b: f32| v >= a.min(b) && v <= a.max(b);
+      within(p.x, pa.x, pb.x) && within(p.y, pa.y, pb.y) && within(p.z, pa.z, pb.z)
+    };
+    let pos = if self.locked[a] { pa } else {
+      match optimal(&q) {
+        Some(p) if between(p) => p,
+        _ => {
+          let mid = (pa + pb) * 0.5;
+          *[pa, pb, mid].iter().min_by(|x, y| {
+            error(&q, **x).partial_cmp(&error(&q, **y)).unwrap_or(Ordering::Equal)
+          }).unwrap()
+        }
+      }
+    };
+
+    self.heap.push(Collapse {
+      cost: error(&q, pos),
+      a,
+      b,
+      pos,
+      stamps: (self.stamps[a], self.stamps[b]),
+    });
+  }
+
+  // Whether the collapse keeps the surface manifold and doesn't turn any
+  // triangle around
+  fn allowed (&self, c: &Collapse) -> bool {
+    // The only vertices next to both have to be the ones of the triangles
+    // of the edge, otherwise the collapse pinches the surface
+    let na = self.neighbors(c.a);
+    let nb = self.neighbors(c.b);
+    let mut shared = 0;
+    for &t in self.faces[c.a].iter() {
+      if self.alive[t] && self.triangles[t].contains(&c.b) { shared += 1; }
+    }
+    if na.iter().filter(|v| nb.contains(v)).count() != shared { return false; }
+
+    for &v in [c.a, c.b].iter() {
+      for &t in self.faces[v].iter() {
+        if !self.alive[t] { continue; }
+        let tri = self.triangles[t];
+        if tri.contains(&c.a) && tri.contains(&c.b) { continue; }
+
+        let before = self.normal(tri);
+        let mut moved = [self.vertices[tri[0]].pos, self.vertices[tri[1]].pos, self.vertices[tri[2]].pos];
+        for i in 0 .. 3 {
+          if tri[i] == v { moved[i] = c.pos; }
+        }
+        let after = (moved[1] - moved[0]).cross(moved[2] - moved[0]);
+        if after.dot(before) <= 0.0 { return false; }
+      }
+    }
+    true
+  }
+
+  // Returns how many triangles were removed
+  fn collapse (&mut self, c: &Collapse) -> usize {
+    let (a, b) = (c.a, c.b);
+
+    let normal = self.vertices[a].normal + self.vertices[b].normal;
+    self.vertices[a].pos = c.pos;
+    if normal.magnitude2() > 0.0 { self.vertices[a].normal = normal.normalize(); }
+    self.quadrics[a] = add(&self.quadrics[a], &self.quadrics[b]);
+
+    let mut removed = 0;
+    let faces = ::std::mem::take(&mut self.faces[b]);
+    for t in faces {
+      if !self.alive[t] { continue; }
+      if self.triangles[t].contains(&a) {
+        self.alive[t] = false;
+        removed += 1;
+      } else {
+        for i in 0 .. 3 {
+          if self.triangles[t][i] == b { self.triangles[t][i] = a; }
+        }
+        self.faces[a].push(t);
+      }
+    }
+
+    self.stamps[a] += 1;
+    self.stamps[b] += 1;
+    for n in self.neighbors(a) {
+      self.push(a, n);
+    }
+    removed
+  }
+}
+
+impl Mesh {
+  // Collapses edges until there are params.triangles or the error of the
+  // next one is more than params.error. The normals are averaged, the
+  // material of the vertex that stays is kept.
+  pub fn simplify (&mut self, params: &Simplify, locked: &dyn Fn(&Vertex) -> bool) {
+    let n = self.vertices.len();
+    let triangles: Vec<[usize; 3]> = self.indices.chunks(3).map(|t| {
+      [t[0] as usize, t[1] as usize, t[2] as usize]
+    }).collect();
+
+    let mut s = Simplifier {
+      locked: self.vertices.iter().map(locked).collect(),
+      vertices: &mut self.vertices,
+      alive: vec![true; triangles.len()],
+      faces: vec![vec![]; n],
+      quadrics: vec![[0.0; 10]; n],
+      stamps: vec![0; n],
+      heap: BinaryHeap::new(),
+      triangles,
+    };
+
+    let mut edges: Vec<(usize, usize)> = vec![];
+    for (t, &tri) in s.triangles.iter().enumerate() {
+      let normal = s.normal(tri);
+      let q = if normal.magnitude2() > 0.0 {
+        plane(normal.normalize(), s.vertices[tri[0]].pos)
+      } else { [0.0; 10] };
+
+      for i in 0 .. 3 {
+        let v = tri[i];
+        s.faces[v].push(t);
+        s.quadrics[v] = add(&s.quadrics[v], &q);
+
+        let u = tri[(i + 1) % 3];
+        edges.push((v.min(u), v.max(u)));
+      }
+    }
+
+    // Edges of only one triangle are on the border, and those of more than
+    // two aren't manifold, the vertices of both stay
+    edges.sort();
+    let mut i = 0;
+    while i < edges.len() {
+      let mut j = i;
+      while j < edges.len() && edges[j] == edges[i] { j += 1; }
+      let (a, b) = edges[i];
+      if j - i != 2 {
+        s.locked[a] = true;
+        s.locked[b] = true;
+      }
+      i = j;
+    }
+    edges.dedup();
+    for &(a, b) in edges.iter() {
+      s.push(a, b);
+    }
+
+    let mut count = s.triangles.len();
+    while count > params.triangles {
+      let c = match s.heap.pop() {
+        Some(c) => c,
+        None => break,
+      };
+      if (s.stamps[c.a], s.stamps[c.b]) != c.stamps { continue; }
+      if c.cost > params.error { break; }
+      if !s.allowed(&c) { continue; }
+      count -= s.collapse(&c);
+    }
+
+    // Only the vertices that are still used
+    let mut remap = vec![-1i64; n];
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    for (t, tri) in s.triangles.iter().enumerate() {
+      if !s.alive[t] { continue; }
+      for &v in tri.iter() {
+        if remap[v] < 0 {
+          remap[v] = vertices.len() as i64;
+          let old = &s.vertices[v];
+          vertices.push(Vertex { pos: old.pos, normal: old.normal, material: old.material, ao: old.ao });
+        }
+        indices.push(remap[v] as u32);
+      }
+    }
+
+    self.vertices = vertices;
+    self.indices = indices;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mesher::Mesher;
+  use marching_cubes::{MarchingCubes, Smoothing};
+  use voxel_source::sdf::{Sphere, Vector};
+
+  const CENTER: [f32; 3] = [9.3, 8.6, 7.7];
+  const RADIUS: f32 = 6.2;
+
+  // Marching cubes of a sphere, cut by the positive x face of the chunk
+  fn sphere (x: f32) -> Mesh {
+    let sphere = Sphere { center: Vector::new(x, CENTER[1], CENTER[2]), radius: RADIUS, material: 0 };
+    MarchingCubes { size: 16, smoothing: Smoothing::None, manifold: false }.mesh(&sphere)
+  }
+
+  // How far from the sphere the vertices are
+  fn deviation (mesh: &Mesh, x: f32) -> f32 {
+    let center = Vector3::new(x, CENTER[1], CENTER[2]);
+    mesh.vertices.iter().map(|v| ((v.pos - center).magnitude() - RADIUS).abs()).fold(0.0, f32::max)
+  }
+
+  #[test]
+  fn keeps_the_triangle_budget () {
+    let mut mesh = sphere(CENTER[0]);
+    let budget = mesh.indices.len() / 3 / 4;
+    mesh.simplify(&Simplify { triangles: budget, error: f32::INFINITY }, &|_| false);
+
+    assert!(mesh.indices.len() / 3 <= budget);
+    let report = mesh.validate();
+    assert!(report.is_valid() && report.boundary_loops.is_empty(), "{}", report);
+  }
+
+  #[test]
+  fn keeps_the_error_and_the_locked_vertices () {
+    let x = 13.4;
+    let original = sphere(x);
+    let mut mesh = sphere(x);
+    let error = 0.01;
+
+    // The border at the face of the chunk stays by itself, the ones in the
+    // other half are locked
+    let locked = |v: &Vertex| v.pos.x < x - 2.0;
+    mesh.simplify(&Simplify { triangles: 0, error }, &locked);
+
+    assert!(mesh.indices.len() < original.indices.len());
+    let report = mesh.validate();
+    assert!(report.is_valid(), "{}", report);
+    assert_eq!(report.boundary_loops.len(), original.validate().boundary_loops.len());
+    assert!(deviation(&mesh, x) <= deviation(&original, x) + error.sqrt());
+
+    for v in original.vertices.iter().filter(|v| locked(v) || v.pos.x >= 16.0) {
+      assert!(mesh.vertices.iter().any(|u| u.pos == v.pos), "{:?} moved", v.pos);
+    }
+  }
+}
@@ -0,0 +1,242 @@
+
+// Checks on the output of the meshers. Chunk meshes are open at the faces
+// of the chunk, so boundary loops are reported but are not an error.
+
+use std::fmt;
+use std::collections::HashMap;
+
+use super::{Mesh, InnerSpace};
+
+// Triangles with less area than this are degenerate too
+const MIN_AREA: f32 = 1e-8;
+
+#[derive(Debug, Default)]
+pub struct Report {
+  // Triangles with an index past the vertices
+  pub out_of_range: Vec<usize>,
+  // Triangles that use a vertex more than once
+  pub repeated: Vec<usize>,
+  // Triangles with three vertices but no area, like when several of them
+  // are on the same voxel
+  pub zero_area: Vec<usize>,
+  // Vertices with a position or normal that is NaN or infinite
+  pub non_finite: Vec<usize>,
+  // Edges of more than two triangles
+  pub non_manifold: Vec<(u32, u32)>,
+  // Edges that two triangles go along the same way, so one of them faces
+  // the other side
+  pub flipped: Vec<(u32, u32)>,
+  // The chains of edges of only one triangle, as vertices
+  pub boundary_loops: Vec<Vec<u32>>,
+  // Indices not multiple of three
+  pub trailing: usize,
+}
+
+impl Report {
+  // Nothing wrong, but it may have holes, see boundary_loops
+  pub fn is_valid (&self) -> bool {
+    self.out_of_range.is_empty() &&
+    self.repeated.is_empty() &&
+    self.zero_area.is_empty() &&
+    self.non_finite.is_empty() &&
+    self.non_manifold.is_empty() &&
+    self.flipped.is_empty() &&
+    self.trailing == 0
+  }
+}
+
+impl fmt::Display for Report {
+  fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f,
+      "{} out of range, {} repeated vertices, {} zero area, {} not finite, \
+       {} non manifold edges, {} flipped edges, {} boundary loops, {} trailing indices",
+      self.out_of_range.len(), self.repeated.len(), self.zero_area.len(),
+      self.non_finite.len(), self.non_manifold.len(), self.flipped.len(),
+      self.boundary_loops.len(), self.trailing)
+  }
+}
+
+impl Mesh {
+  pub fn validate (&self) -> Report {
+    let mut report = Report::default();
+    let n = self.vertices.len() as u32;
+
+    let finite: Vec<bool> = self.vertices.iter().map(|v| {
+      let values = [v.pos.x, v.pos.y, v.pos.z, v.normal.x, v.normal.y, v.normal.z];
+      values.iter().all(|f| f.is_finite())
+    }).collect();
+    report.non_finite = (0 .. finite.len()).filter(|&i| !finite[i]).collect();
+
+    report.trailing = self.indices.len() % 3;
+
+    // How many triangles go along each edge, each way
+    let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+
+    for (i, t) in self.indices.chunks(3).enumerate() {
+      if t.len() < 3 { break; }
+
+      if t.iter().any(|&index| index >= n) {
+        report.out_of_range.push(i);
+        continue;
+      }
+      if t[0] == t[1] || t[1] == t[2] || t[2] == t[0] {
+        report.repeated.push(i);
+        continue;
+      }
+
+      // The ones with bad positions are reported already
+      let p = |k: usize| self.vertices[t[k] as usize].pos;
+      let area = (p(1) - p(0)).cross(p(2) - p(0)).magnitude() / 2.0;
+      if area < MIN_AREA && t.iter().all(|&v| finite[v as usize]) {
+        report.zero_area.push(i);
+      }
+
+      for k in 0 .. 3 {
+        *edges.entry((t[k], t[(k+1) % 3])).or_insert(0) += 1;
+      }
+    }
+
+    // The border goes along the edges of only one triangle
+    let mut border: HashMap<u32, Vec<u32>> = HashMap::new();
+
+    let mut keys: Vec<&(u32, u32)> = edges.keys().collect();
+    keys.sort();
+    for &&(a, b) in keys.iter() {
+      let forward = edges[&(a, b)];
+      let backward = edges.get(&(b, a)).cloned().unwrap_or(0);
+
+      // Each edge once, from the side with the smaller vertex
+      if backward > 0 && a > b { continue; }
+
+      if forward + backward > 2 {
+        report.non_manifold.push((a, b));
+      } else if forward == 2 {
+        report.flipped.push((a, b));
+      } else if backward == 0 {
+        border.entry(a).or_default().push(b);
+      }
+    }
+
+    // Follow the border edges into chains
+    let mut starts: Vec<u32> = border.keys().cloned().collect();
+    starts.sort();
+    for &first in starts.iter() {
+      while !border[&first].is_empty() {
+        let mut chain = vec![];
+        let mut start = first;
+        while let Some(next) = border.get_mut(&start).and_then(|ends| ends.pop()) {
+          chain.push(start);
+          start = next;
+        }
+        report.boundary_loops.push(chain);
+      }
+    }
+
+    report
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mesh::{Mesh, Vertex, Vector3};
+  use mesher::Mesher;
+  use blocky::Blocky;
+  use surfnet::SurfNet;
+  use marching_cubes::{MarchingCubes, MarchingTetrahedra, Smoothing};
+  use dual_contouring::DualContouring;
+  use voxel_source::{VoxelSource, SphereSource, TerrainSource, CaveSource, Translate};
+  use voxel_source::sdf::{Sphere, Torus, Vector};
+
+  const SIZE: i32 = 16;
+
+  fn meshers () -> Vec<(&'static str, Box<dyn Mesher>)> {
+    let size = SIZE;
+    vec![
+      ("blocky", Box::new(Blocky { size, greedy: false })),
+      ("greedy blocky", Box::new(Blocky { size, greedy: true })),
+      ("surfnet", Box::new(SurfNet { size: size as u16, smooth: 7, density: false })),
+      ("density surfnet", Box::new(SurfNet { size: size as u16, smooth: 0, density: true })),
+      ("marching cubes", Box::new(MarchingCubes { size, smoothing: Smoothing::None, manifold: false })),
+      ("smooth marching cubes", Box::new(MarchingCubes { size, smoothing: Smoothing::Box(2), manifold: false })),
+      ("manifold marching cubes", Box::new(MarchingCubes { size, smoothing: Smoothing::None, manifold: true })),
+      ("marching tetrahedra", Box::new(MarchingTetrahedra { size, smoothing: Smoothing::Gaussian(2) })),
+      ("dual contouring", Box::new(DualContouring { size })),
+    ]
+  }
+
+  fn sources () -> Vec<(&'static str, Box<dyn VoxelSource>)> {
+    vec![
+      ("sphere", Box::new(Sphere { center: Vector::new(7.3, 8.6, 7.7), radius: 5.2, material: 0 })),
+      // With densities of exactly zero on some voxels
+      ("voxel sphere", Box::new(SphereSource { x: 8, y: 8, z: 8, r: 5 })),
+      ("torus", Box::new(Torus { center: Vector::new(8.1, 7.4, 8.3), major: 4.6, minor: 1.7, material: 1 })),
+      ("terrain", Box::new(Translate { source: CaveSource::new(TerrainSource::new(1), 1), x: 0, y: -8, z: 0 })),
+    ]
+  }
+
+  // The defects the meshers are known to make, as non manifold edges and
+  // zero area triangles
+  fn expected (mesher: &str, source: &str) -> (usize, usize) {
+    match (mesher, source) {
+      // The dual meshers have one vertex per cell, so where the torus goes
+      // through a cell twice both parts join at that vertex, and the edges
+      // around it are of more than two triangles
+      ("surfnet", "torus") | ("density surfnet", "torus") | ("dual contouring", "torus") => (5, 0),
+      // Marching cubes puts the vertices on the voxels with a density of
+      // zero, all the triangles around them collapse there. The blur moves
+      // the densities away from zero.
+      ("marching cubes", "voxel sphere") | ("manifold marching cubes", "voxel sphere") => (0, 48),
+      _ => (0, 0),
+    }
+  }
+
+  #[test]
+  fn meshers_make_valid_meshes () {
+    for (mesher_name, mut mesher) in meshers() {
+      for &(source_name, ref source) in sources().iter() {
+        let report = mesher.mesh(source.as_ref()).validate();
+        let (non_manifold, zero_area) = expected(mesher_name, source_name);
+        let name = format!("{} of {}: {}", mesher_name, source_name, report);
+
+        assert_eq!(report.non_manifold.len(), non_manifold, "{}", name);
+        assert_eq!(report.zero_area.len(), zero_area, "{}", name);
+        assert!(report.out_of_range.is_empty() && report.repeated.is_empty(), "{}", name);
+        assert!(report.non_finite.is_empty() && report.flipped.is_empty(), "{}", name);
+        assert_eq!(report.trailing, 0, "{}", name);
+      }
+    }
+  }
+
+  #[test]
+  fn finds_broken_meshes () {
+    let vertex = |x: f32, y: f32, z: f32| Vertex::with_material(Vector3::new(x, y, z), 0);
+    let mut mesh = Mesh::new();
+    mesh.vertices = vec![
+      vertex(0.0, 0.0, 0.0), vertex(1.0, 0.0, 0.0), vertex(0.0, 1.0, 0.0),
+      vertex(1.0, 1.0, 0.0), vertex(2.0, 0.0, 0.0),
+    ];
+    mesh.indices = vec![0, 1, 2, 1, 3, 2];
+    let report = mesh.validate();
+    assert!(report.is_valid(), "{}", report);
+    assert_eq!(report.boundary_loops.len(), 1);
+    assert_eq!(report.boundary_loops[0].len(), 4);
+
+    // A third triangle on the diagonal
+    mesh.indices.extend_from_slice(&[1, 2, 4]);
+    assert_eq!(mesh.validate().non_manifold, vec![(1, 2)]);
+
+    // The second one the wrong way
+    mesh.indices = vec![0, 1, 2, 1, 2, 3];
+    assert_eq!(mesh.validate().flipped, vec![(1, 2)]);
+
+    mesh.vertices.push(vertex(f32::NAN, 0.0, 0.0));
+    mesh.indices = vec![0, 1, 4, 0, 0, 1, 0, 1, 6, 0, 1, 5, 0];
+    let report = mesh.validate();
+    assert_eq!(report.zero_area, vec![0]);
+    assert_eq!(report.repeated, vec![1]);
+    assert_eq!(report.out_of_range, vec![2]);
+    assert_eq!(report.non_finite, vec![5]);
+    assert_eq!(report.trailing, 1);
+    assert!(!report.is_valid());
+  }
+}
@@ -4,24 +4,45 @@ use mesh::Mesh;
 
 pub trait Mesher {
   fn mesh (&mut self, source: &VoxelSource) -> Mesh;
+
+  // Voxels per side of the chunks it meshes
+  fn size (&self) -> i32;
+
+  // How many voxels outside of the chunk it reads, at each side
+  fn padding (&self) -> i32 { 1 }
+
+  // Closes the cracks on the faces of the chunk next to chunks with more
+  // detail, changing the mesh from mesh. The source samples the chunk at
+  // the most detail of the faces. Nothing if the mesher can't do it.
+  fn transition (&mut self, _mesh: &mut Mesh, _source: &dyn VoxelSource, _faces: &[Transition]) {}
+}
+
+// A side of a chunk next to chunks with more detail
+pub struct Transition {
+  // 0, 1 or 2 for x, y or z
+  pub axis: usize,
+  // At size in the axis, otherwise at 0
+  pub positive: bool,
+  // How many times smaller the neighbor's voxels are
+  pub detail: i32,
+  // Which voxel squares of the face are next to that neighbor, size*size of
+  // them. The face's axes are the next two after axis, so for y they are
+  // z and x, and the index is u + v*size.
+  pub cells: Vec<bool>,
 }
 
 pub fn calculate_normals (mesh: &mut Mesh) {
   use mesh::{Vector3, InnerSpace};
 
-  let high = mesh.indices.len()/3-1;
-
   // Reset all normals
   for vertex in mesh.vertices.iter_mut() {
     vertex.normal = Vector3::new(0.0, 0.0, 0.0);
   }
 
-  for ii in 0 .. high {
-    let i = ii*3;
-
-    let aa = mesh.indices[i  ] as usize;
-    let bb = mesh.indices[i+1] as usize;
-    let cc = mesh.indices[i+2] as usize;
+  for t in mesh.indices.chunks(3) {
+    let aa = t[0] as usize;
+    let bb = t[1] as usize;
+    let cc = t[2] as usize;
 
     let a = mesh.vertices[aa].pos;
     let b = mesh.vertices[bb].pos;
@@ -35,8 +56,11 @@ pub fn calculate_normals (mesh: &mut Mesh) {
     mesh.vertices[cc].normal += n;
   }
 
-  // Normalize all normals
+  // Normalize all normals. Vertices without triangles, or only with flat
+  // ones, have none.
   for vertex in mesh.vertices.iter_mut() {
-    vertex.normal = vertex.normal.normalize();
+    if vertex.normal.magnitude2() > 0.0 {
+      vertex.normal = vertex.normal.normalize();
+    }
   }
 }
@@ -1,40 +1,117 @@
 
 use mesher::{Mesher, calculate_normals};
-use voxel_source::VoxelSource;
-use cgmath::Vector3;
+use voxel_source::{VoxelSource, Region, Voxel};
+use cgmath::{Vector3, InnerSpace};
 use mesh::{Mesh, Vertex};
 
+// The 8 voxels around a vertex
+const CORNERS: [(i32, i32, i32); 8] = [
+    (0, 0, 0), (0, 0, 1), (0, 1, 0), (0, 1, 1),
+    (1, 0, 0), (1, 0, 1), (1, 1, 0), (1, 1, 1),
+];
+
 pub struct SurfNet {
     pub size: u16,
     pub smooth: u16,
+    // Places the vertices at the average of the crossings of the surface
+    // with the edges of their cell, and takes the normals from the gradient
+    // of the densities. Otherwise they start at the corner of the cell and
+    // only the smoothing moves them.
+    pub density: bool,
 }
 
-pub struct Builder<'a> {
+pub struct Builder {
     size: i32,
     smooth: u16,
-    source: &'a VoxelSource,
+    density: bool,
+    // From -1, with a voxel more at each side for the gradients, and one
+    // more at the end for the last vertices
+    region: Region,
     positions: Vec<(i32, i32, i32)>,
     vertices: Vec<Vector3<f32>>,
     previous: Vec<Vector3<f32>>,
+    materials: Vec<u8>,
     mesh: Mesh,
     indexmap: Vec<i32>,
 }
 
-impl<'a> Builder<'a> {
-    pub fn new (params: &SurfNet, source: &'a VoxelSource) -> Self {
+impl Builder {
+    pub fn new (params: &SurfNet, source: &dyn VoxelSource) -> Self {
         let sz = params.size as usize;
+        let rs = params.size as i32 + 3;
+        let mut region = Region::new([-1, -1, -1], [rs, rs, rs], 1);
+        source.fill_region(&mut region);
         Builder {
             size: params.size as i32,
             smooth: params.smooth,
-            source: source,
+            density: params.density,
+            region,
             positions: vec![],
             previous: vec![],
             vertices: vec![],
+            materials: vec![],
             mesh: Mesh::new(),
             indexmap: vec![-1; sz*sz*sz],
         }
     }
 
+    fn voxel (&self, x: i32, y: i32, z: i32) -> Voxel {
+        self.region.get(x+1, y+1, z+1)
+    }
+
+    // Points towards where the density grows, into the solid
+    fn gradient (&self, x: i32, y: i32, z: i32) -> Vector3<f32> {
+        Vector3::new(
+            self.voxel(x+1, y, z).density - self.voxel(x-1, y, z).density,
+            self.voxel(x, y+1, z).density - self.voxel(x, y-1, z).density,
+            self.voxel(x, y, z+1).density - self.voxel(x, y, z-1).density
+        )
+    }
+
+    // Trilinear interpolation of the gradients of the voxels around
+    fn normal (&self, pos: Vector3<f32>) -> Vector3<f32> {
+        let max = self.size - 1;
+        let x = (pos.x.floor() as i32).max(0).min(max);
+        let y = (pos.y.floor() as i32).max(0).min(max);
+        let z = (pos.z.floor() as i32).max(0).min(max);
+
+        let mut g = Vector3::new(0.0, 0.0, 0.0);
+        for &(dx, dy, dz) in CORNERS.iter() {
+            let weight = |d: i32, t: f32| if d == 1 { t } else { 1.0 - t };
+            let w = weight(dx, pos.x - x as f32)
+                * weight(dy, pos.y - y as f32)
+                * weight(dz, pos.z - z as f32);
+            g += self.gradient(x+dx, y+dy, z+dz) * w;
+        }
+
+        if g.magnitude2() > 0.0 { -g.normalize() } else { g }
+    }
+
+    // The average of the points where the surface crosses the edges of
+    // the cell
+    fn crossings (&self, x: i32, y: i32, z: i32) -> Vector3<f32> {
+        let mut sum = Vector3::new(0.0, 0.0, 0.0);
+        let mut count = 0;
+
+        // Corners that differ in one coordinate make an edge
+        for a in 0 .. 8 {
+            for &bit in [1, 2, 4].iter() {
+                if a & bit != 0 { continue; }
+                let (ca, cb) = (CORNERS[a], CORNERS[a | bit]);
+                let da = self.voxel(x+ca.0, y+ca.1, z+ca.2).density;
+                let db = self.voxel(x+cb.0, y+cb.1, z+cb.2).density;
+                if (da > 0.0) == (db > 0.0) { continue; }
+
+                let pa = Vector3::new((x+ca.0) as f32, (y+ca.1) as f32, (z+ca.2) as f32);
+                let pb = Vector3::new((x+cb.0) as f32, (y+cb.1) as f32, (z+cb.2) as f32);
+                sum += pa + (pb - pa) * (da / (da - db));
+                count += 1;
+            }
+        }
+
+        sum / count as f32
+    }
+
     fn index_at(&self, x: i32, y: i32, z: i32) -> i32 {
         let sz = self.size;
         if x >= 0 && y >= 0 && z >= 0 && x < sz && y < sz && z < sz {
@@ -48,16 +125,21 @@ impl<'a> Builder<'a> {
     }
 
     fn create_vertex (&mut self, x: i32, y: i32, z: i32) {
-        // How many voxels adjacent to this vertex are not empty
+        // How many voxels adjacent to this vertex are not empty, and the
+        // material of the solid one that is closest to the surface
         let mut count = 0;
-        if self.source.get(x, y, z) { count+=1; }
-        if self.source.get(x, y, z+1) { count+=1; }
-        if self.source.get(x, y+1, z) { count+=1; }
-        if self.source.get(x, y+1, z+1) { count+=1; }
-        if self.source.get(x+1, y, z) { count+=1; }
-        if self.source.get(x+1, y, z+1) { count+=1; }
-        if self.source.get(x+1, y+1, z) { count+=1; }
-        if self.source.get(x+1, y+1, z+1) { count+=1; }
+        let mut material = 0;
+        let mut closest = f32::MAX;
+        for &(dx, dy, dz) in CORNERS.iter() {
+            let voxel = self.voxel(x+dx, y+dy, z+dz);
+            if voxel.is_solid() {
+                count += 1;
+                if voxel.density < closest {
+                    closest = voxel.density;
+                    material = voxel.material;
+                }
+            }
+        }
 
         // If voxels are not empty nor full,
         // then there's a vertex here.
@@ -69,7 +151,13 @@ impl<'a> Builder<'a> {
             let index = self.positions.len();
             self.indexmap[ix] = index as i32;
             self.positions.push( (x as i32, y as i32, z as i32) );
-            self.vertices.push( Vector3::new(x as f32, y as f32, z as f32) );
+            let pos = if self.density {
+                self.crossings(x, y, z)
+            } else {
+                Vector3::new(x as f32, y as f32, z as f32)
+            };
+            self.vertices.push(pos);
+            self.materials.push(material);
         }
     }
 
@@ -86,9 +174,9 @@ impl<'a> Builder<'a> {
         }
 
         if vertices == 4 {
-            let pos = self.source.get(x+1, y+1, z+1);
+            let pos = self.voxel(x+1, y+1, z+1).is_solid();
             let p = offs[3];
-            let neg = self.source.get(x+p[0], y+p[1], z+p[2]);
+            let neg = self.voxel(x+p[0], y+p[1], z+p[2]).is_solid();
 
             if pos != neg {
                 let o = if neg {[0,1,2, 2,1,3]} else {[2,1,0, 3,1,2]};
@@ -96,7 +184,7 @@ impl<'a> Builder<'a> {
                 let mut ix = [0; 6];
                 for i in 0..6 {
                     let ii = o[i];
-                    ix[i] = self.index_at_off(x,y,z,offs[ii]) as u16;
+                    ix[i] = self.index_at_off(x,y,z,offs[ii]) as u32;
                 }
 
                 self.mesh.indices.push(ix[0]);
@@ -161,9 +249,9 @@ impl<'a> Builder<'a> {
         }
 
         match self {
-            &mut Builder{ref mut mesh, ref mut vertices, ..} => {
-                mesh.vertices = vertices.iter().map(
-                    |pos| Vertex::from_pos(*pos)
+            &mut Builder{ref mut mesh, ref mut vertices, ref materials, ..} => {
+                mesh.vertices = vertices.iter().zip(materials.iter()).map(
+                    |(pos, mat)| Vertex::with_material(*pos, *mat)
                 ).collect()
             }
         }
@@ -194,7 +282,15 @@ impl<'a> Builder<'a> {
             }
         }
 
-        calculate_normals(&mut self.mesh);
+        // After the smoothing, where the vertices ended up
+        if self.density {
+            for i in 0 .. self.mesh.vertices.len() {
+                let normal = self.normal(self.mesh.vertices[i].pos);
+                self.mesh.vertices[i].normal = normal;
+            }
+        } else {
+            calculate_normals(&mut self.mesh);
+        }
     }
 }
 
@@ -213,4 +309,6 @@ impl Mesher for SurfNet {
 
         builder.mesh
     }
+
+    fn size (&self) -> i32 { self.size as i32 }
 }
@@ -0,0 +1,316 @@
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use cgmath::{Vector3, InnerSpace};
+
+use super::{VoxelSource, Region};
+use super::noise::{Perlin, Fractal, Rng, hash};
+
+// Carves caves out of another source. There are two kinds:
+//
+// - Caverns, big rounded spaces where a 3D noise is over a threshold.
+// - Worms, tunnels that follow a path steered by noise (Perlin worms).
+//
+// Everything is computed in world coordinates and the worms are generated
+// on a grid independent of the chunks, so caves continue across chunk
+// borders. The same seed always gives the same caves.
+pub struct CaveSource<S: VoxelSource> {
+  pub source: S,
+
+  pub cavern: Fractal,
+  // Noise values over this are cavern, in [-1, 1]
+  pub cavern_threshold: f32,
+  // Converts noise over the threshold into voxels of depth
+  pub cavern_scale: f32,
+
+  // The worms are cached as they are generated, so these have to be set
+  // before sampling. Each cell of this size can spawn one worm.
+  pub worm_cell: i32,
+  // Probability of a cell having a worm
+  pub worm_chance: f32,
+  pub worm_segments: u32,
+  pub worm_step: f32,
+  pub worm_radius: f32,
+  // How fast the worms change direction, in radians per segment
+  pub worm_turn: f32,
+  // Frequency of the noise that steers the worms
+  pub worm_frequency: f32,
+
+  seed: u32,
+  cavern_noise: Perlin,
+  worm_noise: Perlin,
+
+  // Worm segments that can reach each cell, computed on demand
+  cache: RefCell<SegmentCache>,
+}
+
+// How many cells keep their segments. A chunk touches a few cells at most,
+// the rest are for the chunks around it.
+const CACHE_CELLS: usize = 64;
+
+// The segments of the cells used most recently, the oldest are dropped.
+// Each cell keeps when it was last used, so using one is O(1) and only
+// dropping one looks at all of them.
+struct SegmentCache {
+  cells: HashMap<(i32, i32, i32), Cached>,
+  clock: u64,
+}
+
+struct Cached {
+  segments: Rc<Vec<Segment>>,
+  used: u64,
+}
+
+impl SegmentCache {
+  fn new () -> Self {
+    SegmentCache { cells: HashMap::with_capacity(CACHE_CELLS), clock: 0 }
+  }
+
+  fn get (&mut self, key: (i32, i32, i32)) -> Option<Rc<Vec<Segment>>> {
+    self.clock += 1;
+    let clock = self.clock;
+    self.cells.get_mut(&key).map(|cell| {
+      cell.used = clock;
+      cell.segments.clone()
+    })
+  }
+
+  fn insert (&mut self, key: (i32, i32, i32), segments: Rc<Vec<Segment>>) {
+    if self.cells.len() >= CACHE_CELLS {
+      let oldest = self.cells.iter().min_by_key(|&(_, cell)| cell.used).map(|(&key, _)| key);
+      if let Some(oldest) = oldest {
+        self.cells.remove(&oldest);
+      }
+    }
+    self.clock += 1;
+    self.cells.insert(key, Cached { segments, used: self.clock });
+  }
+}
+
+struct Segment {
+  a: Vector3<f32>,
+  b: Vector3<f32>,
+  radius: f32,
+}
+
+impl Segment {
+  // Positive inside the tunnel
+  fn density (&self, p: Vector3<f32>) -> f32 {
+    let ab = self.b - self.a;
+    let t = ((p - self.a).dot(ab) / ab.dot(ab)).clamp(0.0, 1.0);
+    self.radius - (p - (self.a + ab*t)).magnitude()
+  }
+
+  fn touches (&self, min: Vector3<f32>, max: Vector3<f32>) -> bool {
+    let r = self.radius;
+    self.a.x.min(self.b.x) - r <= max.x && self.a.x.max(self.b.x) + r >= min.x &&
+    self.a.y.min(self.b.y) - r <= max.y && self.a.y.max(self.b.y) + r >= min.y &&
+    self.a.z.min(self.b.z) - r <= max.z && self.a.z.max(self.b.z) + r >= min.z
+  }
+}
+
+impl<S: VoxelSource> CaveSource<S> {
+  pub fn new (source: S, seed: u32) -> Self {
+    CaveSource {
+      source,
+
+      cavern: Fractal {
+        octaves: 3,
+        frequency: 1.0/48.0,
+        lacunarity: 2.0,
+        gain: 0.5,
+      },
+      cavern_threshold: 0.35,
+      cavern_scale: 40.0,
+
+      worm_cell: 64,
+      worm_chance: 0.5,
+      worm_segments: 48,
+      worm_step: 2.0,
+      worm_radius: 2.5,
+      worm_turn: 0.6,
+      worm_frequency: 1.0/32.0,
+
+      seed,
+      cavern_noise: Perlin::new(seed.wrapping_add(2)),
+      worm_noise: Perlin::new(seed.wrapping_add(3)),
+      cache: RefCell::new(SegmentCache::new()),
+    }
+  }
+
+  fn cell_of (&self, v: i32) -> i32 { v.div_euclid(self.worm_cell) }
+
+  // The worm that starts in the given cell, if there is any
+  fn worm (&self, cx: i32, cy: i32, cz: i32) -> Vec<Segment> {
+    let mut rng = Rng::new(hash(self.seed, cx, cy, cz));
+    if rng.next_f32() >= self.worm_chance { return vec![]; }
+
+    let cell = self.worm_cell as f32;
+    let mut p = Vector3::new(
+      (cx as f32 + rng.next_f32()) * cell,
+      (cy as f32 + rng.next_f32()) * cell,
+      (cz as f32 + rng.next_f32()) * cell
+    );
+    let mut yaw = rng.next_f32() * 2.0 * ::std::f32::consts::PI;
+    let mut pitch = (rng.next_f32() - 0.5) * 0.5;
+
+    // Each worm reads the noise at a different place
+    let offset = rng.next_f32() * 1000.0;
+
+    let mut segments = Vec::with_capacity(self.worm_segments as usize);
+    for i in 0 .. self.worm_segments {
+      let f = self.worm_frequency;
+      let t = i as f32 * f * self.worm_step + offset;
+      yaw += self.worm_noise.get3(p.x*f, p.y*f, t) * self.worm_turn;
+      pitch += self.worm_noise.get3(p.z*f, t, p.y*f) * self.worm_turn * 0.5;
+      // Keep them mostly horizontal
+      pitch = pitch.clamp(-0.6, 0.6);
+
+      let dir = Vector3::new(
+        yaw.cos() * pitch.cos(),
+        pitch.sin(),
+        yaw.sin() * pitch.cos()
+      );
+      let next = p + dir * self.worm_step;
+
+      // Slightly varying thickness
+      let r = self.worm_noise.get3(t, p.x*f, p.z*f);
+      segments.push(Segment {
+        a: p, b: next,
+        radius: self.worm_radius * (1.0 + r*0.5),
+      });
+      p = next;
+    }
+
+    segments
+  }
+
+  fn segments (&self, cx: i32, cy: i32, cz: i32) -> Rc<Vec<Segment>> {
+    if let Some(segments) = self.cache.borrow_mut().get((cx, cy, cz)) {
+      return segments;
+    }
+
+    let cell = self.worm_cell as f32;
+    let min = Vector3::new(cx as f32, cy as f32, cz as f32) * cell;
+    let max = min + Vector3::new(cell, cell, cell);
+
+    // How many cells away a worm can go from where it started
+    let reach = self.worm_segments as f32 * self.worm_step + self.worm_radius * 1.5;
+    let n = (reach / cell).ceil() as i32;
+
+    let mut segments = vec![];
+    for x in cx-n .. cx+n+1 {
+      for y in cy-n .. cy+n+1 {
+        for z in cz-n .. cz+n+1 {
+          for segment in self.worm(x, y, z) {
+            if segment.touches(min, max) {
+              segments.push(segment);
+            }
+          }
+        }
+      }
+    }
+
+    let segments = Rc::new(segments);
+    self.cache.borrow_mut().insert((cx, cy, cz), segments.clone());
+    segments
+  }
+
+  // Positive inside caves
+  pub fn cave_density (&self, x: i32, y: i32, z: i32) -> f32 {
+    let segments = self.segments(self.cell_of(x), self.cell_of(y), self.cell_of(z));
+    self.carve(x, y, z, &segments)
+  }
+
+  // The cave density with the segments of the voxel's cell
+  fn carve (&self, x: i32, y: i32, z: i32, segments: &[Segment]) -> f32 {
+    let n = self.cavern.get3(&self.cavern_noise, x as f32, y as f32, z as f32);
+    let mut d = (n - self.cavern_threshold) * self.cavern_scale;
+
+    let p = Vector3::new(x as f32, y as f32, z as f32);
+    for segment in segments.iter() {
+      d = d.max(segment.density(p));
+    }
+
+    d
+  }
+}
+
+impl<S: VoxelSource> VoxelSource for CaveSource<S> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.source.density(x, y, z).min(-self.cave_density(x, y, z))
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    self.source.material(x, y, z)
+  }
+
+  // The segments of the cells under the region are looked up once, not
+  // for every voxel
+  fn fill_region (&self, region: &mut Region) {
+    self.source.fill_region(region);
+
+    let last = |i: usize| region.origin[i] + (region.size[i] - 1) * region.step;
+    let lo = [self.cell_of(region.origin[0]), self.cell_of(region.origin[1]), self.cell_of(region.origin[2])];
+    let hi = [self.cell_of(last(0)), self.cell_of(last(1)), self.cell_of(last(2))];
+    let (nx, ny) = (hi[0] - lo[0] + 1, hi[1] - lo[1] + 1);
+
+    let mut cells = vec![];
+    for cz in lo[2] .. hi[2]+1 {
+      for cy in lo[1] .. hi[1]+1 {
+        for cx in lo[0] .. hi[0]+1 {
+          cells.push(self.segments(cx, cy, cz));
+        }
+      }
+    }
+
+    let [sx, sy, sz] = region.size;
+    for k in 0 .. sz {
+      for j in 0 .. sy {
+        for i in 0 .. sx {
+          let [x, y, z] = region.position(i, j, k);
+          let cell = (self.cell_of(x) - lo[0]) + (self.cell_of(y) - lo[1])*nx + (self.cell_of(z) - lo[2])*nx*ny;
+          let ix = region.index(i, j, k);
+          let voxel = &mut region.voxels[ix];
+          voxel.density = voxel.density.min(-self.carve(x, y, z, &cells[cell as usize]));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use voxel_source::TerrainSource;
+
+  // Sampling far and wide keeps the cache bounded, and the dropped cells
+  // give the same caves when they come back
+  #[test]
+  fn cache_stays_bounded () {
+    let caves = CaveSource::new(TerrainSource::new(1), 1);
+    let first: Vec<f32> = (0 .. 8).map(|i| caves.cave_density(i * 9, -20, 5)).collect();
+
+    for x in 0 .. 12 { for z in 0 .. 12 {
+      caves.cave_density(x * caves.worm_cell, -20, z * caves.worm_cell);
+    } }
+    assert!(caves.cache.borrow().cells.len() <= CACHE_CELLS);
+
+    let again: Vec<f32> = (0 .. 8).map(|i| caves.cave_density(i * 9, -20, 5)).collect();
+    assert_eq!(first, again);
+  }
+
+  #[test]
+  fn fill_region_matches_the_voxels () {
+    let caves = CaveSource::new(TerrainSource::new(1), 1);
+    // Across cells, with the region's step
+    let mut region = Region::new([caves.worm_cell - 6, -30, -5], [7, 6, 8], 2);
+    caves.fill_region(&mut region);
+    for k in 0 .. 8 { for j in 0 .. 6 { for i in 0 .. 7 {
+      let [x, y, z] = region.position(i, j, k);
+      assert_eq!(region.density(i, j, k), caves.density(x, y, z));
+    } } }
+  }
+}
@@ -0,0 +1,344 @@
+
+use std::ops::Neg;
+
+use super::{VoxelSource, Region, Voxel};
+
+// Combinators to build scenes out of other sources. All of them are generic,
+// so they nest freely, and work on boxed sources as well:
+//
+//     Union(
+//       Subtract(Box::new(terrain), Box::new(crater)),
+//       Translate { source: island, x: 0, y: 64, z: 0 }
+//     )
+
+// Fills the region with the first source and the copy with the second
+fn fill_both<A: VoxelSource, B: VoxelSource> (a: &A, b: &B, region: &mut Region) -> Region {
+  let mut other = Region::new(region.origin, region.size, region.step);
+  a.fill_region(region);
+  b.fill_region(&mut other);
+  other
+}
+
+// Solid where any of the two is solid
+pub struct Union<A: VoxelSource, B: VoxelSource>(pub A, pub B);
+
+impl<A: VoxelSource, B: VoxelSource> VoxelSource for Union<A, B> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.0.density(x, y, z).max(self.1.density(x, y, z))
+  }
+
+  // The material of whichever is more solid
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    if self.0.density(x, y, z) >= self.1.density(x, y, z) {
+      self.0.material(x, y, z)
+    } else {
+      self.1.material(x, y, z)
+    }
+  }
+
+  fn fill_region (&self, region: &mut Region) {
+    let other = fill_both(&self.0, &self.1, region);
+    for (voxel, b) in region.voxels.iter_mut().zip(other.voxels.iter()) {
+      if b.density > voxel.density { *voxel = *b; }
+    }
+  }
+}
+
+// Solid where both are solid, with the materials of the first one
+pub struct Intersection<A: VoxelSource, B: VoxelSource>(pub A, pub B);
+
+impl<A: VoxelSource, B: VoxelSource> VoxelSource for Intersection<A, B> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.0.density(x, y, z).min(self.1.density(x, y, z))
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    self.0.material(x, y, z)
+  }
+
+  fn fill_region (&self, region: &mut Region) {
+    let other = fill_both(&self.0, &self.1, region);
+    for (voxel, b) in region.voxels.iter_mut().zip(other.voxels.iter()) {
+      voxel.density = voxel.density.min(b.density);
+    }
+  }
+}
+
+// The first one with the second one carved out of it
+pub struct Subtract<A: VoxelSource, B: VoxelSource>(pub A, pub B);
+
+impl<A: VoxelSource, B: VoxelSource> VoxelSource for Subtract<A, B> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.0.density(x, y, z).min(-self.1.density(x, y, z))
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    self.0.material(x, y, z)
+  }
+
+  fn fill_region (&self, region: &mut Region) {
+    let other = fill_both(&self.0, &self.1, region);
+    for (voxel, b) in region.voxels.iter_mut().zip(other.voxels.iter()) {
+      voxel.density = voxel.density.min(-b.density);
+    }
+  }
+}
+
+// Moves the source by the given amount of voxels
+pub struct Translate<S: VoxelSource> {
+  pub source: S,
+  pub x: i32,
+  pub y: i32,
+  pub z: i32,
+}
+
+impl<S: VoxelSource> VoxelSource for Translate<S> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.source.density(x - self.x, y - self.y, z - self.z)
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    self.source.material(x - self.x, y - self.y, z - self.z)
+  }
+
+  fn normal (&self, p: [f32; 3]) -> Option<[f32; 3]> {
+    self.source.normal([p[0] - self.x as f32, p[1] - self.y as f32, p[2] - self.z as f32])
+  }
+
+  fn fill_region (&self, region: &mut Region) {
+    let origin = region.origin;
+    region.origin = [origin[0] - self.x, origin[1] - self.y, origin[2] - self.z];
+    self.source.fill_region(region);
+    region.origin = origin;
+  }
+}
+
+// Scales the source around the origin. Voxels that don't fall on the
+// source's grid are interpolated from the 8 around them.
+pub struct Scale<S: VoxelSource> {
+  pub source: S,
+  pub factor: f32,
+}
+
+impl<S: VoxelSource> Scale<S> {
+  fn position (&self, v: i32) -> (i32, f32) {
+    let p = v as f32 / self.factor;
+    let f = p.floor();
+    (f as i32, p - f)
+  }
+
+  // The density at the voxel, from the densities of the source around it
+  fn interpolate<F: Fn(i32, i32, i32) -> f32> (&self, x: i32, y: i32, z: i32, density: F) -> f32 {
+    let (x, fx) = self.position(x);
+    let (y, fy) = self.position(y);
+    let (z, fz) = self.position(z);
+
+    let s = density;
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a)*t;
+
+    let x0 = lerp(s(x, y, z), s(x+1, y, z), fx);
+    let x1 = lerp(s(x, y, z+1), s(x+1, y, z+1), fx);
+    let x2 = lerp(s(x, y+1, z), s(x+1, y+1, z), fx);
+    let x3 = lerp(s(x, y+1, z+1), s(x+1, y+1, z+1), fx);
+
+    let z0 = lerp(x0, x1, fz);
+    let z1 = lerp(x2, x3, fz);
+
+    // Distances grow with the scale
+    lerp(z0, z1, fy) * self.factor
+  }
+
+  // The source voxel whose material the voxel takes
+  fn nearest (&self, v: i32) -> i32 {
+    (v as f32 / self.factor).round() as i32
+  }
+}
+
+impl<S: VoxelSource> VoxelSource for Scale<S> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.interpolate(x, y, z, |x, y, z| self.source.density(x, y, z))
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    self.source.material(self.nearest(x), self.nearest(y), self.nearest(z))
+  }
+
+  // The surface only gets bigger, it keeps it's direction
+  fn normal (&self, p: [f32; 3]) -> Option<[f32; 3]> {
+    let f = self.factor;
+    self.source.normal([p[0] / f, p[1] / f, p[2] / f])
+  }
+
+  // The source is sampled once, in the box of it's voxels that the region
+  // reads. When shrinking that box has more voxels than the region, so
+  // they are sampled one by one instead.
+  fn fill_region (&self, region: &mut Region) {
+    if self.factor < 1.0 {
+      region.fill(|x, y, z| Voxel { density: self.density(x, y, z), material: self.material(x, y, z) });
+      return;
+    }
+
+    let last = |i: usize| region.origin[i] + (region.size[i] - 1) * region.step;
+    let min = [
+      self.position(region.origin[0]).0,
+      self.position(region.origin[1]).0,
+      self.position(region.origin[2]).0,
+    ];
+    let size = |i: usize| self.position(last(i)).0 + 2 - min[i];
+    let mut source = Region::new(min, [size(0), size(1), size(2)], 1);
+    self.source.fill_region(&mut source);
+
+    region.fill(|x, y, z| {
+      let density = self.interpolate(x, y, z, |x, y, z| source.density(x - min[0], y - min[1], z - min[2]));
+      let (i, j, k) = (self.nearest(x) - min[0], self.nearest(y) - min[1], self.nearest(z) - min[2]);
+      Voxel { density, material: source.material(i, j, k) }
+    });
+  }
+}
+
+#[derive(Clone, Copy)]
+pub enum Axis { X, Y, Z }
+
+// Rotates the source a number of quarter turns around an axis through the
+// origin. Positive turns are counter clockwise looking from the positive
+// side of the axis.
+pub struct Rotate<S: VoxelSource> {
+  pub source: S,
+  pub axis: Axis,
+  pub turns: i32,
+}
+
+// The point turned clockwise around the axis, so turning the position of
+// a voxel gives where in the source it comes from
+fn turn<T: Copy + Neg<Output=T>> (axis: Axis, turns: i32, p: [T; 3]) -> [T; 3] {
+  let [x, y, z] = p;
+  let (mut a, mut b) = match axis {
+    Axis::X => (y, z),
+    Axis::Y => (z, x),
+    Axis::Z => (x, y),
+  };
+  for _ in 0 .. turns.rem_euclid(4) {
+    let t = a;
+    a = b;
+    b = -t;
+  }
+  match axis {
+    Axis::X => [x, a, b],
+    Axis::Y => [b, y, a],
+    Axis::Z => [a, b, z],
+  }
+}
+
+impl<S: VoxelSource> Rotate<S> {
+  // Where in the source the voxel comes from, it's the inverse rotation
+  fn position (&self, x: i32, y: i32, z: i32) -> (i32, i32, i32) {
+    let [x, y, z] = turn(self.axis, self.turns, [x, y, z]);
+    (x, y, z)
+  }
+}
+
+impl<S: VoxelSource> VoxelSource for Rotate<S> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    let (x, y, z) = self.position(x, y, z);
+    self.source.density(x, y, z)
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    let (x, y, z) = self.position(x, y, z);
+    self.source.material(x, y, z)
+  }
+
+  // The normal of the source at the same point, turned back
+  fn normal (&self, p: [f32; 3]) -> Option<[f32; 3]> {
+    let n = self.source.normal(turn(self.axis, self.turns, p))?;
+    Some(turn(self.axis, -self.turns, n))
+  }
+}
+
+// Repeats the part of the source between 0 and the period, in the axes
+// where the period is not 0.
+pub struct Repeat<S: VoxelSource> {
+  pub source: S,
+  pub x: i32,
+  pub y: i32,
+  pub z: i32,
+}
+
+impl<S: VoxelSource> Repeat<S> {
+  fn position (&self, x: i32, y: i32, z: i32) -> (i32, i32, i32) {
+    fn wrap (v: i32, period: i32) -> i32 {
+      if period == 0 { v } else { v.rem_euclid(period) }
+    }
+    (wrap(x, self.x), wrap(y, self.y), wrap(z, self.z))
+  }
+}
+
+impl<S: VoxelSource> VoxelSource for Repeat<S> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    let (x, y, z) = self.position(x, y, z);
+    self.source.density(x, y, z)
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    let (x, y, z) = self.position(x, y, z);
+    self.source.material(x, y, z)
+  }
+
+  fn normal (&self, p: [f32; 3]) -> Option<[f32; 3]> {
+    let wrap = |v: f32, period: i32| {
+      if period == 0 { v } else { v.rem_euclid(period as f32) }
+    };
+    self.source.normal([wrap(p[0], self.x), wrap(p[1], self.y), wrap(p[2], self.z)])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use voxel_source::sdf::{Sphere, Vector};
+
+  fn sphere () -> Sphere {
+    Sphere { center: Vector::new(3.3, 4.6, 2.7), radius: 2.2, material: 1 }
+  }
+
+  #[test]
+  fn scale_fills_the_region_like_the_voxels () {
+    for &factor in [0.5, 1.0, 2.5].iter() {
+      for &step in [1, 2].iter() {
+        let scale = Scale { source: sphere(), factor };
+        let mut region = Region::new([-3, -2, -4], [9, 11, 10], step);
+        scale.fill_region(&mut region);
+
+        for k in 0 .. 10 { for j in 0 .. 11 { for i in 0 .. 9 {
+          let [x, y, z] = region.position(i, j, k);
+          let voxel = region.get(i, j, k);
+          assert!((voxel.density - scale.density(x, y, z)).abs() < 1e-5, "{} at {} {} {}", factor, x, y, z);
+          assert_eq!(voxel.material, scale.material(x, y, z));
+        } } }
+      }
+    }
+  }
+
+  #[test]
+  fn transforms_keep_the_normals () {
+    let close = |a: Option<[f32; 3]>, b: [f32; 3]| {
+      let a = a.unwrap();
+      (0 .. 3).all(|i| (a[i] - b[i]).abs() < 1e-3)
+    };
+    let n = sphere().normal([5.5, 4.6, 2.7]).unwrap();
+    assert!(close(Some(n), [1.0, 0.0, 0.0]));
+
+    let scale = Scale { source: sphere(), factor: 2.0 };
+    assert!(close(scale.normal([11.0, 9.2, 5.4]), n));
+
+    // A quarter turn around z takes x to y
+    let rotate = Rotate { source: sphere(), axis: Axis::Z, turns: 1 };
+    let [x, y, z] = turn(Axis::Z, -1, [5.5, 4.6, 2.7]);
+    assert!(close(rotate.normal([x, y, z]), [0.0, 1.0, 0.0]));
+    assert_eq!(rotate.density(x.round() as i32, y.round() as i32, z.round() as i32),
+      sphere().density(6, 5, 3));
+
+    let repeat = Repeat { source: sphere(), x: 8, y: 0, z: 0 };
+    assert!(close(repeat.normal([21.5, 4.6, 2.7]), n));
+  }
+}
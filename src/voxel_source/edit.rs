@@ -0,0 +1,165 @@
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::{VoxelSource, Voxel, Region};
+
+// Edits are grouped in cubes of this many voxels per side
+pub const EDIT_CHUNK: i32 = 16;
+
+// An axis aligned box of voxels, both ends are included
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Bounds {
+  pub min: [i32; 3],
+  pub max: [i32; 3],
+}
+
+impl Bounds {
+  pub fn point (x: i32, y: i32, z: i32) -> Self {
+    Bounds { min: [x, y, z], max: [x, y, z] }
+  }
+
+  // The cube containing a sphere
+  pub fn around (center: [f32; 3], radius: f32) -> Self {
+    let min = |i: usize| (center[i] - radius).floor() as i32;
+    let max = |i: usize| (center[i] + radius).ceil() as i32;
+    Bounds { min: [min(0), min(1), min(2)], max: [max(0), max(1), max(2)] }
+  }
+
+  pub fn union (&self, other: &Bounds) -> Bounds {
+    let mut b = *self;
+    for i in 0 .. 3 {
+      b.min[i] = b.min[i].min(other.min[i]);
+      b.max[i] = b.max[i].max(other.max[i]);
+    }
+    b
+  }
+
+  pub fn intersects (&self, other: &Bounds) -> bool {
+    (0 .. 3).all(|i| self.min[i] <= other.max[i] && self.max[i] >= other.min[i])
+  }
+}
+
+fn chunk_of (x: i32, y: i32, z: i32) -> ((i32, i32, i32), u16) {
+  let n = EDIT_CHUNK;
+  let key = (x.div_euclid(n), y.div_euclid(n), z.div_euclid(n));
+  let (lx, ly, lz) = (x.rem_euclid(n), y.rem_euclid(n), z.rem_euclid(n));
+  (key, (lx + ly*n + lz*n*n) as u16)
+}
+
+// Modifications on top of another source. Only the voxels that were changed
+// are stored, sparsely in each edit chunk, everything else comes from the
+// wrapped source.
+pub struct EditSource<S: VoxelSource> {
+  pub source: S,
+  chunks: BTreeMap<(i32, i32, i32), HashMap<u16, Voxel>>,
+}
+
+impl<S: VoxelSource> EditSource<S> {
+  pub fn new (source: S) -> Self {
+    EditSource { source, chunks: BTreeMap::new() }
+  }
+
+  // The edited voxel, None if it comes from the source
+  pub fn edit (&self, x: i32, y: i32, z: i32) -> Option<Voxel> {
+    let (key, ix) = chunk_of(x, y, z);
+    self.chunks.get(&key).and_then(|chunk| chunk.get(&ix).cloned())
+  }
+
+  // Returns the voxels that changed
+  pub fn set (&mut self, x: i32, y: i32, z: i32, voxel: Voxel) -> Bounds {
+    let (key, ix) = chunk_of(x, y, z);
+    self.chunks.entry(key).or_default().insert(ix, voxel);
+    Bounds::point(x, y, z)
+  }
+
+  // Sets or clears the edit, None goes back to the source
+  pub fn restore (&mut self, x: i32, y: i32, z: i32, voxel: Option<Voxel>) -> Bounds {
+    match voxel {
+      Some(voxel) => self.set(x, y, z, voxel),
+      None => self.clear(x, y, z),
+    }
+  }
+
+  // Discards the edit, the voxel goes back to the source
+  pub fn clear (&mut self, x: i32, y: i32, z: i32) -> Bounds {
+    let (key, ix) = chunk_of(x, y, z);
+    let empty = match self.chunks.get_mut(&key) {
+      Some(chunk) => { chunk.remove(&ix); chunk.is_empty() },
+      None => false,
+    };
+    if empty { self.chunks.remove(&key); }
+    Bounds::point(x, y, z)
+  }
+}
+
+impl<S: VoxelSource> VoxelSource for EditSource<S> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    match self.edit(x, y, z) {
+      Some(voxel) => voxel.density,
+      None => self.source.density(x, y, z),
+    }
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    match self.edit(x, y, z) {
+      Some(voxel) => voxel.material,
+      None => self.source.material(x, y, z),
+    }
+  }
+
+  // Only where the voxels around weren't edited
+  fn normal (&self, p: [f32; 3]) -> Option<[f32; 3]> {
+    let (x, y, z) = (p[0].floor() as i32, p[1].floor() as i32, p[2].floor() as i32);
+    for i in 0 .. 8 {
+      if self.edit(x + (i & 1), y + ((i >> 1) & 1), z + (i >> 2)).is_some() {
+        return None;
+      }
+    }
+    self.source.normal(p)
+  }
+
+  fn fill_region (&self, region: &mut Region) {
+    self.source.fill_region(region);
+
+    // Then only visit the edits that fall inside the region
+    let n = EDIT_CHUNK;
+    let step = region.step;
+    let last = |i: usize| region.origin[i] + (region.size[i] - 1) * step;
+    let max = [last(0), last(1), last(2)];
+    let lo = (
+      region.origin[0].div_euclid(n),
+      region.origin[1].div_euclid(n),
+      region.origin[2].div_euclid(n)
+    );
+    let hi = (max[0].div_euclid(n), max[1].div_euclid(n), max[2].div_euclid(n));
+
+    for (&(cx, cy, cz), chunk) in self.chunks.range(lo .. (hi.0, hi.1, hi.2 + 1)) {
+      // The range is lexicographic, it also has chunks outside in y and z
+      if cy < lo.1 || cy > hi.1 || cz < lo.2 || cz > hi.2 { continue; }
+
+      for (&ix, voxel) in chunk.iter() {
+        let ix = ix as i32;
+        let p = [
+          cx*n + ix % n,
+          cy*n + (ix / n) % n,
+          cz*n + ix / (n*n),
+        ];
+
+        let mut local = [0; 3];
+        let mut inside = true;
+        for i in 0 .. 3 {
+          let d = p[i] - region.origin[i];
+          if d < 0 || d % step != 0 || d / step >= region.size[i] {
+            inside = false;
+          }
+          local[i] = d / step;
+        }
+
+        if inside {
+          let index = region.index(local[0], local[1], local[2]);
+          region.voxels[index] = *voxel;
+        }
+      }
+    }
+  }
+}
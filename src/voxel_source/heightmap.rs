@@ -0,0 +1,132 @@
+
+use image::{self, ImageResult};
+
+use super::{VoxelSource, Region, GRASS, SOILSAND};
+
+// The materials a material map can pick, from black to white
+const MATERIALS: [u8; 2] = [GRASS, SOILSAND];
+
+// What happens outside of the image
+#[derive(Clone, Copy)]
+pub enum Wrap {
+  // The image repeats
+  Tile,
+  // The border pixels are extended forever
+  Clamp,
+}
+
+// Terrain from a grayscale image, black is the lowest and white the highest.
+// Heights are interpolated between pixels.
+pub struct HeightmapSource {
+  // Voxels per pixel
+  pub horizontal_scale: f32,
+  // Voxels between a black and a white pixel
+  pub vertical_scale: f32,
+  // Height of a black pixel
+  pub offset: f32,
+  pub wrap: Wrap,
+  // Thickness of the top material layer
+  pub grass_depth: f32,
+
+  // Size of the image
+  columns: i32,
+  rows: i32,
+  // In [0, 1]
+  heights: Vec<f32>,
+
+  // Material of the top layer of each column, if there's a material map
+  materials: Option<(i32, i32, Vec<u8>)>,
+}
+
+impl HeightmapSource {
+  pub fn open (path: &str) -> ImageResult<Self> {
+    let img = image::open(path)?.to_luma();
+    let (width, height) = img.dimensions();
+    let heights = img.into_raw().iter().map(|v| *v as f32 / 255.0).collect();
+
+    Ok(HeightmapSource {
+      horizontal_scale: 1.0,
+      vertical_scale: 64.0,
+      offset: 0.0,
+      wrap: Wrap::Clamp,
+      grass_depth: 2.0,
+
+      columns: width as i32,
+      rows: height as i32,
+      heights,
+      materials: None,
+    })
+  }
+
+  // The gray levels of the image are split evenly between the materials,
+  // in the order of MATERIALS, so black is grass and white is soil. It's
+  // stretched to cover the same area as the heights.
+  pub fn load_materials (&mut self, path: &str) -> ImageResult<()> {
+    let img = image::open(path)?.to_luma();
+    let (width, height) = img.dimensions();
+    let materials = img.into_raw().iter().map(|&v| {
+      MATERIALS[v as usize * MATERIALS.len() / 256]
+    }).collect();
+    self.materials = Some((width as i32, height as i32, materials));
+    Ok(())
+  }
+
+  fn wrap (&self, v: i32, size: i32) -> i32 {
+    match self.wrap {
+      Wrap::Tile => v.rem_euclid(size),
+      Wrap::Clamp => v.max(0).min(size - 1),
+    }
+  }
+
+  fn pixel (&self, x: i32, y: i32) -> f32 {
+    let x = self.wrap(x, self.columns);
+    let y = self.wrap(y, self.rows);
+    self.heights[(x + y*self.columns) as usize]
+  }
+
+  pub fn height (&self, x: i32, z: i32) -> f32 {
+    let px = x as f32 / self.horizontal_scale;
+    let pz = z as f32 / self.horizontal_scale;
+    let (fx, fz) = (px.floor(), pz.floor());
+    let (ix, iz) = (fx as i32, fz as i32);
+    let (tx, tz) = (px - fx, pz - fz);
+
+    let a = self.pixel(ix, iz) * (1.0-tx) + self.pixel(ix+1, iz) * tx;
+    let b = self.pixel(ix, iz+1) * (1.0-tx) + self.pixel(ix+1, iz+1) * tx;
+    let v = a * (1.0-tz) + b * tz;
+
+    self.offset + v * self.vertical_scale
+  }
+
+  fn column_material (&self, x: i32, z: i32) -> u8 {
+    match self.materials {
+      Some((width, height, ref data)) => {
+        // Pixel of the heightmap, then the same place in the material map
+        let px = (x as f32 / self.horizontal_scale).floor() as i32;
+        let pz = (z as f32 / self.horizontal_scale).floor() as i32;
+        let mx = self.wrap(px, self.columns) * width / self.columns;
+        let mz = self.wrap(pz, self.rows) * height / self.rows;
+        data[(mx + mz*width) as usize]
+      },
+      None => GRASS,
+    }
+  }
+}
+
+impl VoxelSource for HeightmapSource {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.height(x, z) - y as f32
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    if self.density(x, y, z) < self.grass_depth {
+      self.column_material(x, z)
+    } else { SOILSAND }
+  }
+
+  fn fill_region (&self, region: &mut Region) {
+    region.fill_heights(self.grass_depth, |x, z| {
+      (self.height(x, z), self.column_material(x, z))
+    });
+  }
+}
@@ -0,0 +1,144 @@
+
+pub mod noise;
+pub mod sdf;
+mod terrain;
+mod caves;
+mod csg;
+mod heightmap;
+mod region;
+mod edit;
+
+pub use self::region::{Voxel, Region};
+pub use self::edit::{EditSource, Bounds};
+pub use self::terrain::TerrainSource;
+pub use self::caves::CaveSource;
+pub use self::heightmap::{HeightmapSource, Wrap};
+pub use self::csg::{Union, Intersection, Subtract, Translate, Scale, Rotate, Axis, Repeat};
+
+// Material ids, the terrain shader picks a texture with them
+pub const GRASS: u8 = 0;
+pub const SOILSAND: u8 = 1;
+
+// Densities are signed: positive values are solid, negative values are air
+// and the surface lies where the density crosses zero. Analytic sources
+// should return something close to the distance to the surface, in voxels.
+pub trait VoxelSource {
+  fn density(&self, x: i32, y: i32, z: i32) -> f32;
+
+  // Only meaningful for solid voxels
+  fn material(&self, _x: i32, _y: i32, _z: i32) -> u8 { GRASS }
+
+  // The exact surface normal at a point between voxels, pointing out of the
+  // solid, for sources that know it. Otherwise meshers estimate it from the
+  // densities around.
+  fn normal(&self, _p: [f32; 3]) -> Option<[f32; 3]> { None }
+
+  // Samples all the voxels of the region at once. Sources that can reuse
+  // work between neighbouring voxels should override this.
+  fn fill_region(&self, region: &mut Region) {
+    region.fill(|x, y, z| Voxel {
+      density: self.density(x, y, z),
+      material: self.material(x, y, z),
+    });
+  }
+}
+
+// So that combinators can hold sources of different types
+impl<S: VoxelSource + ?Sized> VoxelSource for Box<S> {
+  fn density(&self, x: i32, y: i32, z: i32) -> f32 {
+    (**self).density(x, y, z)
+  }
+
+  fn material(&self, x: i32, y: i32, z: i32) -> u8 {
+    (**self).material(x, y, z)
+  }
+
+  fn normal(&self, p: [f32; 3]) -> Option<[f32; 3]> {
+    (**self).normal(p)
+  }
+
+  fn fill_region(&self, region: &mut Region) {
+    (**self).fill_region(region)
+  }
+}
+
+// Boolean occupancy, for sources that only know if a voxel is full or empty.
+// Wrap them in Solid to use them as a VoxelSource.
+pub trait SolidSource {
+  fn get(&self, x: i32, y: i32, z: i32) -> bool;
+}
+
+pub struct Solid<S: SolidSource>(pub S);
+
+impl<S: SolidSource> VoxelSource for Solid<S> {
+  fn density(&self, x: i32, y: i32, z: i32) -> f32 {
+    if self.0.get(x, y, z) {1.0} else {-1.0}
+  }
+}
+
+pub struct SphereSource {
+  pub x: i32,
+  pub y: i32,
+  pub z: i32,
+  pub r: i32,
+}
+
+impl SolidSource for SphereSource {
+  fn get(&self, ix: i32, iy: i32, iz: i32) -> bool {
+    let (x, y, z) = (ix-self.x, iy-self.y, iz-self.z);
+    let d2 = x*x + y*y + z*z;
+    let r2 = self.r * self.r;
+    return d2 < r2;
+  }
+}
+
+impl VoxelSource for SphereSource {
+  fn density(&self, ix: i32, iy: i32, iz: i32) -> f32 {
+    let (x, y, z) = (ix-self.x, iy-self.y, iz-self.z);
+    let d2 = (x*x + y*y + z*z) as f32;
+    self.r as f32 - d2.sqrt()
+  }
+
+  fn fill_region(&self, region: &mut Region) {
+    region.fill(|x, y, z| {
+      let (x, y, z) = (x-self.x, y-self.y, z-self.z);
+      let d2 = (x*x + y*y + z*z) as f32;
+      Voxel { density: self.r as f32 - d2.sqrt(), material: GRASS }
+    });
+  }
+}
+
+pub struct SineSource {
+  pub amplitude: f32,
+  pub magnitude: f32,
+  pub bias: f32,
+}
+
+impl SineSource {
+  fn height(&self, x: i32, z: i32) -> f32 {
+    let xv = (x as f32*self.amplitude).cos();
+    let zv = (z as f32*self.amplitude).cos();
+    xv*zv*self.magnitude + self.bias
+  }
+}
+
+impl SolidSource for SineSource {
+  fn get(&self, x: i32, y: i32, z: i32) -> bool {
+    y < self.height(x, z) as i32
+  }
+}
+
+impl VoxelSource for SineSource {
+  fn density(&self, x: i32, y: i32, z: i32) -> f32 {
+    self.height(x, z) - y as f32
+  }
+
+  // Two voxels of grass on top, soil and sand under them
+  fn material(&self, x: i32, y: i32, z: i32) -> u8 {
+    if self.density(x, y, z) < 2.0 { GRASS } else { SOILSAND }
+  }
+
+  fn fill_region(&self, region: &mut Region) {
+    region.fill_heights(2.0, |x, z| (self.height(x, z), GRASS));
+  }
+}
@@ -0,0 +1,178 @@
+
+// Seeded gradient noise, based on Ken Perlin's improved noise:
+// http://mrl.nyu.edu/~perlin/noise/
+
+// Small xorshift generator, used to shuffle the permutation table and
+// anywhere else a deterministic sequence of numbers is needed.
+pub struct Rng(u32);
+
+impl Rng {
+  pub fn new (seed: u32) -> Self {
+    // xorshift gets stuck on zero
+    Rng(if seed == 0 {0x9E3779B9} else {seed})
+  }
+
+  pub fn next (&mut self) -> u32 {
+    let mut x = self.0;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    self.0 = x;
+    x
+  }
+
+  // Uniform in [0, 1)
+  pub fn next_f32 (&mut self) -> f32 {
+    (self.next() >> 8) as f32 / (1 << 24) as f32
+  }
+}
+
+// Mixes a seed with integer coordinates, to give every cell of a grid
+// its own random sequence regardless of the order they are visited.
+pub fn hash (seed: u32, x: i32, y: i32, z: i32) -> u32 {
+  let mut h = seed ^ 0x2545F491;
+  for &v in [x, y, z].iter() {
+    h ^= v as u32;
+    h = h.wrapping_mul(0x9E3779B1);
+    h ^= h >> 15;
+  }
+  h = h.wrapping_mul(0x85EBCA77);
+  h ^ (h >> 13)
+}
+
+pub struct Perlin {
+  perm: [u8; 512],
+}
+
+fn fade (t: f32) -> f32 { t*t*t*(t*(t*6.0 - 15.0) + 10.0) }
+
+fn lerp (t: f32, a: f32, b: f32) -> f32 { a + t*(b - a) }
+
+fn grad2 (hash: u8, x: f32, y: f32) -> f32 {
+  match hash & 7 {
+    0 =>  x + y,
+    1 => -x + y,
+    2 =>  x - y,
+    3 => -x - y,
+    4 =>  x,
+    5 => -x,
+    6 =>  y,
+    _ => -y,
+  }
+}
+
+fn grad3 (hash: u8, x: f32, y: f32, z: f32) -> f32 {
+  let h = hash & 15;
+  let u = if h < 8 {x} else {y};
+  let v = if h < 4 {y} else if h == 12 || h == 14 {x} else {z};
+  (if h&1 == 0 {u} else {-u}) + (if h&2 == 0 {v} else {-v})
+}
+
+impl Perlin {
+  pub fn new (seed: u32) -> Self {
+    let mut rng = Rng::new(seed);
+    let mut p = [0u8; 256];
+    for (i, v) in p.iter_mut().enumerate() { *v = i as u8; }
+
+    // Fisher-Yates shuffle
+    for i in (1 .. 256).rev() {
+      let j = (rng.next() % (i as u32 + 1)) as usize;
+      p.swap(i, j);
+    }
+
+    let mut perm = [0u8; 512];
+    for i in 0 .. 512 { perm[i] = p[i & 255]; }
+    Perlin { perm }
+  }
+
+  fn at (&self, i: i32) -> usize { self.perm[(i & 511) as usize] as usize }
+
+  // Roughly in [-1, 1]
+  pub fn get2 (&self, x: f32, y: f32) -> f32 {
+    let (fx, fy) = (x.floor(), y.floor());
+    let (xi, yi) = (fx as i32 & 255, fy as i32 & 255);
+    let (x, y) = (x - fx, y - fy);
+    let (u, v) = (fade(x), fade(y));
+
+    let a = self.at(xi) as i32 + yi;
+    let b = self.at(xi+1) as i32 + yi;
+
+    let p = &self.perm;
+    lerp(v,
+      lerp(u, grad2(p[self.at(a)], x, y), grad2(p[self.at(b)], x-1.0, y)),
+      lerp(u, grad2(p[self.at(a+1)], x, y-1.0), grad2(p[self.at(b+1)], x-1.0, y-1.0))
+    )
+  }
+
+  // Roughly in [-1, 1]
+  pub fn get3 (&self, x: f32, y: f32, z: f32) -> f32 {
+    let (fx, fy, fz) = (x.floor(), y.floor(), z.floor());
+    let (xi, yi, zi) = (fx as i32 & 255, fy as i32 & 255, fz as i32 & 255);
+    let (x, y, z) = (x - fx, y - fy, z - fz);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let a  = self.at(xi) as i32 + yi;
+    let aa = self.at(a) as i32 + zi;
+    let ab = self.at(a+1) as i32 + zi;
+    let b  = self.at(xi+1) as i32 + yi;
+    let ba = self.at(b) as i32 + zi;
+    let bb = self.at(b+1) as i32 + zi;
+
+    let p = &self.perm;
+    lerp(w,
+      lerp(v,
+        lerp(u, grad3(p[aa as usize & 511], x, y, z),
+                grad3(p[ba as usize & 511], x-1.0, y, z)),
+        lerp(u, grad3(p[ab as usize & 511], x, y-1.0, z),
+                grad3(p[bb as usize & 511], x-1.0, y-1.0, z))),
+      lerp(v,
+        lerp(u, grad3(p[(aa+1) as usize & 511], x, y, z-1.0),
+                grad3(p[(ba+1) as usize & 511], x-1.0, y, z-1.0)),
+        lerp(u, grad3(p[(ab+1) as usize & 511], x, y-1.0, z-1.0),
+                grad3(p[(bb+1) as usize & 511], x-1.0, y-1.0, z-1.0)))
+    )
+  }
+}
+
+// Fractal brownian motion, adds octaves of noise of increasing frequency
+// and decreasing amplitude. The result is normalized to roughly [-1, 1].
+#[derive(Clone, Copy)]
+pub struct Fractal {
+  pub octaves: u32,
+  // Frequency of the first octave, in cycles per voxel
+  pub frequency: f32,
+  // Frequency multiplier between octaves
+  pub lacunarity: f32,
+  // Amplitude multiplier between octaves
+  pub gain: f32,
+}
+
+impl Fractal {
+  pub fn get2 (&self, noise: &Perlin, x: f32, y: f32) -> f32 {
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = self.frequency;
+    for _ in 0 .. self.octaves {
+      sum += noise.get2(x*frequency, y*frequency) * amplitude;
+      total += amplitude;
+      amplitude *= self.gain;
+      frequency *= self.lacunarity;
+    }
+    if total > 0.0 { sum / total } else { 0.0 }
+  }
+
+  pub fn get3 (&self, noise: &Perlin, x: f32, y: f32, z: f32) -> f32 {
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = self.frequency;
+    for _ in 0 .. self.octaves {
+      sum += noise.get3(x*frequency, y*frequency, z*frequency) * amplitude;
+      total += amplitude;
+      amplitude *= self.gain;
+      frequency *= self.lacunarity;
+    }
+    if total > 0.0 { sum / total } else { 0.0 }
+  }
+}
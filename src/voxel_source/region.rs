@@ -0,0 +1,103 @@
+
+use super::{GRASS, SOILSAND};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Voxel {
+  pub density: f32,
+  pub material: u8,
+}
+
+impl Voxel {
+  pub const AIR: Voxel = Voxel { density: -1.0, material: GRASS };
+
+  pub fn is_solid (&self) -> bool { self.density > 0.0 }
+}
+
+// A box of voxels sampled from a source, meshers fill one of these at once
+// instead of asking the source for every voxel.
+//
+// Voxel (i, j, k) of the region is voxel (origin + (i, j, k)*step) of the
+// source. They are stored with x changing fastest, then y, then z.
+pub struct Region {
+  pub origin: [i32; 3],
+  pub size: [i32; 3],
+  pub step: i32,
+  pub voxels: Vec<Voxel>,
+}
+
+impl Region {
+  pub fn new (origin: [i32; 3], size: [i32; 3], step: i32) -> Self {
+    Region {
+      origin,
+      size,
+      step,
+      voxels: vec![Voxel::AIR; (size[0]*size[1]*size[2]) as usize],
+    }
+  }
+
+  pub fn index (&self, i: i32, j: i32, k: i32) -> usize {
+    (i + j*self.size[0] + k*self.size[0]*self.size[1]) as usize
+  }
+
+  pub fn get (&self, i: i32, j: i32, k: i32) -> Voxel {
+    self.voxels[self.index(i, j, k)]
+  }
+
+  pub fn density (&self, i: i32, j: i32, k: i32) -> f32 {
+    self.voxels[self.index(i, j, k)].density
+  }
+
+  pub fn material (&self, i: i32, j: i32, k: i32) -> u8 {
+    self.voxels[self.index(i, j, k)].material
+  }
+
+  pub fn is_solid (&self, i: i32, j: i32, k: i32) -> bool {
+    self.voxels[self.index(i, j, k)].is_solid()
+  }
+
+  // Source coordinates of a voxel of the region
+  pub fn position (&self, i: i32, j: i32, k: i32) -> [i32; 3] {
+    [
+      self.origin[0] + i*self.step,
+      self.origin[1] + j*self.step,
+      self.origin[2] + k*self.step,
+    ]
+  }
+
+  // Calls f with the source coordinates of every voxel, in storage order
+  pub fn fill<F: FnMut(i32, i32, i32) -> Voxel> (&mut self, mut f: F) {
+    let [sx, sy, sz] = self.size;
+    let mut ix = 0;
+    for k in 0 .. sz {
+      for j in 0 .. sy {
+        for i in 0 .. sx {
+          let [x, y, z] = self.position(i, j, k);
+          self.voxels[ix] = f(x, y, z);
+          ix += 1;
+        }
+      }
+    }
+  }
+
+  // For heightfield sources. column gives the terrain height and the top
+  // material at a column, it's only called once per column. The top layer,
+  // depth voxels thick, gets the top material and the rest is soil.
+  pub fn fill_heights<F: FnMut(i32, i32) -> (f32, u8)> (&mut self, depth: f32, mut column: F) {
+    let [sx, sy, sz] = self.size;
+    for k in 0 .. sz {
+      for i in 0 .. sx {
+        let [x, _, z] = self.position(i, 0, k);
+        let (height, top) = column(x, z);
+        for j in 0 .. sy {
+          let y = self.origin[1] + j*self.step;
+          let density = height - y as f32;
+          let ix = self.index(i, j, k);
+          self.voxels[ix] = Voxel {
+            density,
+            material: if density < depth { top } else { SOILSAND },
+          };
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,262 @@
+
+// Signed distance field primitives, positions and sizes are in voxels.
+// See http://iquilezles.org/www/articles/distfunctions/distfunctions.htm
+//
+// Distance functions are negative inside, the opposite of densities, so
+// every primitive is a VoxelSource whose density is minus the distance.
+
+use cgmath::{Vector2, Vector3, InnerSpace};
+
+use super::VoxelSource;
+
+pub type Vector = Vector3<f32>;
+
+pub trait Sdf {
+  fn distance (&self, p: Vector) -> f32;
+  fn material (&self) -> u8;
+}
+
+macro_rules! sdf_source {
+  ($t:ident) => {
+    impl VoxelSource for $t {
+      fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+        -self.distance(Vector::new(x as f32, y as f32, z as f32))
+      }
+
+      fn material (&self, _x: i32, _y: i32, _z: i32) -> u8 {
+        Sdf::material(self)
+      }
+
+      fn normal (&self, p: [f32; 3]) -> Option<[f32; 3]> {
+        Some(gradient(self, Vector::new(p[0], p[1], p[2])))
+      }
+    }
+  }
+}
+
+// Central differences of the distance, close enough to be exact
+fn gradient<T: Sdf> (sdf: &T, p: Vector) -> [f32; 3] {
+  let e = 0.001;
+  let g = Vector::new(
+    sdf.distance(p + Vector::new(e, 0.0, 0.0)) - sdf.distance(p - Vector::new(e, 0.0, 0.0)),
+    sdf.distance(p + Vector::new(0.0, e, 0.0)) - sdf.distance(p - Vector::new(0.0, e, 0.0)),
+    sdf.distance(p + Vector::new(0.0, 0.0, e)) - sdf.distance(p - Vector::new(0.0, 0.0, e))
+  );
+  let g = if g.magnitude2() > 0.0 { g.normalize() } else { g };
+  [g.x, g.y, g.z]
+}
+
+fn abs (v: Vector) -> Vector { Vector::new(v.x.abs(), v.y.abs(), v.z.abs()) }
+
+fn max0 (v: Vector) -> Vector { Vector::new(v.x.max(0.0), v.y.max(0.0), v.z.max(0.0)) }
+
+pub struct Sphere {
+  pub center: Vector,
+  pub radius: f32,
+  pub material: u8,
+}
+
+impl Sdf for Sphere {
+  fn distance (&self, p: Vector) -> f32 {
+    (p - self.center).magnitude() - self.radius
+  }
+  fn material (&self) -> u8 { self.material }
+}
+
+sdf_source!(Sphere);
+
+// Axis aligned box, half is half of the size in each axis
+pub struct Cuboid {
+  pub center: Vector,
+  pub half: Vector,
+  pub material: u8,
+}
+
+impl Sdf for Cuboid {
+  fn distance (&self, p: Vector) -> f32 {
+    let q = abs(p - self.center) - self.half;
+    max0(q).magnitude() + q.x.max(q.y.max(q.z)).min(0.0)
+  }
+  fn material (&self) -> u8 { self.material }
+}
+
+sdf_source!(Cuboid);
+
+// A box with it's edges rounded with the given radius. The radius is
+// included in half, so it has the same size as the equivalent Cuboid.
+pub struct RoundedCuboid {
+  pub center: Vector,
+  pub half: Vector,
+  pub radius: f32,
+  pub material: u8,
+}
+
+impl Sdf for RoundedCuboid {
+  fn distance (&self, p: Vector) -> f32 {
+    let r = self.radius;
+    let q = abs(p - self.center) - self.half + Vector::new(r, r, r);
+    max0(q).magnitude() + q.x.max(q.y.max(q.z)).min(0.0) - r
+  }
+  fn material (&self) -> u8 { self.material }
+}
+
+sdf_source!(RoundedCuboid);
+
+// A segment from a to b, thickened by the radius
+pub struct Capsule {
+  pub a: Vector,
+  pub b: Vector,
+  pub radius: f32,
+  pub material: u8,
+}
+
+impl Sdf for Capsule {
+  fn distance (&self, p: Vector) -> f32 {
+    let pa = p - self.a;
+    let ba = self.b - self.a;
+    let h = (pa.dot(ba) / ba.dot(ba)).clamp(0.0, 1.0);
+    (pa - ba*h).magnitude() - self.radius
+  }
+  fn material (&self) -> u8 { self.material }
+}
+
+sdf_source!(Capsule);
+
+// Vertical, with flat caps
+pub struct Cylinder {
+  pub center: Vector,
+  pub radius: f32,
+  pub half_height: f32,
+  pub material: u8,
+}
+
+impl Sdf for Cylinder {
+  fn distance (&self, p: Vector) -> f32 {
+    let p = p - self.center;
+    let d = Vector2::new(
+      Vector2::new(p.x, p.z).magnitude() - self.radius,
+      p.y.abs() - self.half_height
+    );
+    d.x.max(d.y).min(0.0) + Vector2::new(d.x.max(0.0), d.y.max(0.0)).magnitude()
+  }
+  fn material (&self) -> u8 { self.material }
+}
+
+sdf_source!(Cylinder);
+
+// Lying flat, around the vertical axis
+pub struct Torus {
+  pub center: Vector,
+  // From the center to the middle of the ring
+  pub major: f32,
+  // Thickness of the ring
+  pub minor: f32,
+  pub material: u8,
+}
+
+impl Sdf for Torus {
+  fn distance (&self, p: Vector) -> f32 {
+    let p = p - self.center;
+    let q = Vector2::new(Vector2::new(p.x, p.z).magnitude() - self.major, p.y);
+    q.magnitude() - self.minor
+  }
+  fn material (&self) -> u8 { self.material }
+}
+
+sdf_source!(Torus);
+
+// Solid on the opposite side of the normal, the plane is at offset voxels
+// from the origin in the direction of the normal
+pub struct Plane {
+  // Unit length
+  normal: Vector,
+  pub offset: f32,
+  pub material: u8,
+}
+
+impl Plane {
+  // None if the normal is zero, it wouldn't have a side
+  pub fn new (normal: Vector, offset: f32, material: u8) -> Option<Plane> {
+    if normal.magnitude2() == 0.0 { return None; }
+    Some(Plane { normal: normal.normalize(), offset, material })
+  }
+}
+
+impl Sdf for Plane {
+  fn distance (&self, p: Vector) -> f32 {
+    p.dot(self.normal) - self.offset
+  }
+  fn material (&self) -> u8 { self.material }
+}
+
+sdf_source!(Plane);
+
+// Polynomial smooth minimum, k is the size of the blending region
+pub fn smooth_min (a: f32, b: f32, k: f32) -> f32 {
+  if k <= 0.0 { return a.min(b); }
+  let h = (0.5 + 0.5*(b - a)/k).clamp(0.0, 1.0);
+  b + (a - b)*h - k*h*(1.0 - h)
+}
+
+pub fn smooth_max (a: f32, b: f32, k: f32) -> f32 {
+  -smooth_min(-a, -b, k)
+}
+
+// The smooth versions of the csg combinators. These work on densities, so
+// they can blend any pair of sources, not only distance fields. The third
+// field is the size of the blending region, in voxels.
+
+pub struct SmoothUnion<A: VoxelSource, B: VoxelSource>(pub A, pub B, pub f32);
+
+impl<A: VoxelSource, B: VoxelSource> VoxelSource for SmoothUnion<A, B> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    smooth_max(self.0.density(x, y, z), self.1.density(x, y, z), self.2)
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    if self.0.density(x, y, z) >= self.1.density(x, y, z) {
+      self.0.material(x, y, z)
+    } else {
+      self.1.material(x, y, z)
+    }
+  }
+}
+
+pub struct SmoothIntersection<A: VoxelSource, B: VoxelSource>(pub A, pub B, pub f32);
+
+impl<A: VoxelSource, B: VoxelSource> VoxelSource for SmoothIntersection<A, B> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    smooth_min(self.0.density(x, y, z), self.1.density(x, y, z), self.2)
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    self.0.material(x, y, z)
+  }
+}
+
+pub struct SmoothSubtract<A: VoxelSource, B: VoxelSource>(pub A, pub B, pub f32);
+
+impl<A: VoxelSource, B: VoxelSource> VoxelSource for SmoothSubtract<A, B> {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    smooth_min(self.0.density(x, y, z), -self.1.density(x, y, z), self.2)
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    self.0.material(x, y, z)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn plane_needs_a_normal () {
+    assert!(Plane::new(Vector::new(0.0, 0.0, 0.0), 1.0, 0).is_none());
+
+    // Not unit length, the distance is still in voxels
+    let plane = Plane::new(Vector::new(0.0, 3.0, 0.0), 2.0, 0).unwrap();
+    assert_eq!(plane.density(5, 0, 7), 2.0);
+    assert_eq!(plane.density(5, 4, 7), -2.0);
+  }
+}
@@ -0,0 +1,98 @@
+
+use super::{VoxelSource, Region, GRASS, SOILSAND};
+use super::noise::{Perlin, Fractal};
+
+// Follows the mapgen design in the readme. A large scale 2D noise, the
+// mountain mask, says where the mountains are. A smaller 2D noise controls
+// the actual height everywhere, but it's exagerated in mountanous areas.
+// Caves are carved afterwards by wrapping this in another source.
+//
+// All distances are in voxels.
+pub struct TerrainSource {
+  // Height of the plains
+  pub base_height: f32,
+
+  pub mountain: Fractal,
+  // Mask values (in [0, 1]) below start are plains, above end are mountains,
+  // and there's a smooth transition in between
+  pub mountain_start: f32,
+  pub mountain_end: f32,
+  // How much a full mountain area is raised over the plains
+  pub mountain_height: f32,
+
+  pub detail: Fractal,
+  // How tall the height noise is in the plains and in the mountains
+  pub plain_amplitude: f32,
+  pub mountain_amplitude: f32,
+
+  // Thickness of the grass layer
+  pub grass_depth: f32,
+
+  mountain_noise: Perlin,
+  detail_noise: Perlin,
+}
+
+fn smoothstep (a: f32, b: f32, x: f32) -> f32 {
+  let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+  t*t*(3.0 - 2.0*t)
+}
+
+impl TerrainSource {
+  pub fn new (seed: u32) -> Self {
+    TerrainSource {
+      base_height: 16.0,
+
+      mountain: Fractal {
+        octaves: 3,
+        frequency: 1.0/1024.0,
+        lacunarity: 2.0,
+        gain: 0.5,
+      },
+      mountain_start: 0.45,
+      mountain_end: 0.7,
+      mountain_height: 32.0,
+
+      detail: Fractal {
+        octaves: 5,
+        frequency: 1.0/128.0,
+        lacunarity: 2.0,
+        gain: 0.5,
+      },
+      plain_amplitude: 6.0,
+      mountain_amplitude: 20.0,
+
+      grass_depth: 2.0,
+
+      mountain_noise: Perlin::new(seed),
+      detail_noise: Perlin::new(seed.wrapping_add(1)),
+    }
+  }
+
+  // In [0, 1], 0 for plains and 1 for mountains
+  pub fn mountain_mask (&self, x: i32, z: i32) -> f32 {
+    let n = self.mountain.get2(&self.mountain_noise, x as f32, z as f32);
+    smoothstep(self.mountain_start, self.mountain_end, n*0.5 + 0.5)
+  }
+
+  pub fn height (&self, x: i32, z: i32) -> f32 {
+    let mask = self.mountain_mask(x, z);
+    let detail = self.detail.get2(&self.detail_noise, x as f32, z as f32);
+    let amplitude = self.plain_amplitude
+                  + (self.mountain_amplitude - self.plain_amplitude) * mask;
+    self.base_height + self.mountain_height * mask + detail * amplitude
+  }
+}
+
+impl VoxelSource for TerrainSource {
+  fn density (&self, x: i32, y: i32, z: i32) -> f32 {
+    self.height(x, z) - y as f32
+  }
+
+  fn material (&self, x: i32, y: i32, z: i32) -> u8 {
+    if self.density(x, y, z) < self.grass_depth { GRASS } else { SOILSAND }
+  }
+
+  fn fill_region (&self, region: &mut Region) {
+    region.fill_heights(self.grass_depth, |x, z| (self.height(x, z), GRASS));
+  }
+}